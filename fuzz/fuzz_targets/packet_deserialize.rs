@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use speedwagon::Packet;
+
+fuzz_target!(|data: &[u8]| {
+    let mut reader = data;
+    while let Ok(packet) = Packet::deserialize(&mut reader) {
+        let mut buf = Vec::new();
+        packet.serialize(&mut buf).unwrap();
+        assert!(buf.len() <= speedwagon::MAX_PACKET_SIZE);
+    }
+});
@@ -0,0 +1,579 @@
+use std::io::{Read, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{
+    decode_err, read_fields, read_payload, DeserializeOptions, Error,
+    Identity, Packet, PacketKind, PacketType, Result, MAX_BATCH_CMDS,
+    NUM_CMD_PARAMS, NUM_STATUS_BYTES,
+};
+
+/// Payload widths for devices that don't use the `NUM_STATUS_BYTES` and
+/// `NUM_CMD_PARAMS` layout. The default config matches the constants.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ProtocolConfig {
+    pub num_status_bytes: usize,
+    pub num_cmd_params: usize,
+    /// Same as `DeserializeOptions::protocol_version`.
+    pub protocol_version: bool,
+    /// Same as `DeserializeOptions::status_len`.
+    pub status_len: bool,
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        Self {
+            num_status_bytes: NUM_STATUS_BYTES,
+            num_cmd_params: NUM_CMD_PARAMS,
+            protocol_version: false,
+            status_len: false,
+        }
+    }
+}
+
+/// Sizes the status payload from the connected device's identity.
+impl From<&Identity> for ProtocolConfig {
+    fn from(identity: &Identity) -> Self {
+        Self {
+            num_status_bytes: identity.num_status_bytes(),
+            ..Default::default()
+        }
+    }
+}
+
+/// The packet types whose payload width depends on the config hold a
+/// `Vec<u8>`, everything else is a regular `PacketType` in `Other`.
+#[derive(Clone, PartialEq, Debug)]
+pub enum ConfigPacketType {
+    Cmd { index: u8, params: Vec<u8> },
+    CmdBatch { cmds: Vec<(u8, Vec<u8>)> },
+    OnStatus(Vec<u8>),
+    Other(PacketType),
+}
+
+impl ConfigPacketType {
+    pub fn kind(&self) -> PacketKind {
+        match self {
+            ConfigPacketType::Cmd {
+                index: _,
+                params: _,
+            } => PacketKind::Cmd,
+            ConfigPacketType::CmdBatch { cmds: _ } => PacketKind::CmdBatch,
+            ConfigPacketType::OnStatus(_) => PacketKind::OnStatus,
+            ConfigPacketType::Other(typ) => typ.kind(),
+        }
+    }
+
+    // The type byte on the wire, vendor packets carry their own
+    fn to_u8(&self) -> u8 {
+        match self {
+            ConfigPacketType::Other(typ) => typ.to_u8(),
+            _ => self.kind() as u8,
+        }
+    }
+
+    fn check(&self, config: &ProtocolConfig) -> Result<()> {
+        let width = |expected: usize, given: usize| {
+            if expected != given {
+                return Err(Error::PayloadWidthMismatch { expected, given });
+            }
+
+            Ok(())
+        };
+
+        match self {
+            ConfigPacketType::Cmd { index: _, params } => {
+                width(config.num_cmd_params, params.len())
+            }
+            ConfigPacketType::CmdBatch { cmds } => {
+                if cmds.len() > MAX_BATCH_CMDS {
+                    return Err(Error::TooManyBatchCmds(cmds.len()));
+                }
+
+                cmds.iter().try_for_each(|(_, params)| {
+                    width(config.num_cmd_params, params.len())
+                })
+            }
+            ConfigPacketType::OnStatus(status) => {
+                width(config.num_status_bytes, status.len())
+            }
+            // The fixed size variants only fit the default widths
+            ConfigPacketType::Other(typ) => {
+                typ.check()?;
+
+                match typ.kind() {
+                    PacketKind::Cmd | PacketKind::CmdBatch => {
+                        width(config.num_cmd_params, NUM_CMD_PARAMS)
+                    }
+                    PacketKind::OnStatus => {
+                        width(config.num_status_bytes, NUM_STATUS_BYTES)
+                    }
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+impl From<PacketType> for ConfigPacketType {
+    fn from(typ: PacketType) -> Self {
+        match typ {
+            PacketType::Cmd { index, params } => ConfigPacketType::Cmd {
+                index,
+                params: params.to_vec(),
+            },
+            PacketType::CmdBatch { cmds } => ConfigPacketType::CmdBatch {
+                cmds: cmds
+                    .into_iter()
+                    .map(|(index, params)| (index, params.to_vec()))
+                    .collect(),
+            },
+            PacketType::OnStatus(status) => {
+                ConfigPacketType::OnStatus(status.to_vec())
+            }
+            typ => ConfigPacketType::Other(typ),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ConfigPacket {
+    id: u16,
+    typ: ConfigPacketType,
+}
+
+impl ConfigPacket {
+    pub fn new(id: u16, typ: ConfigPacketType) -> Self {
+        Self { id, typ }
+    }
+
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn typ(&self) -> &ConfigPacketType {
+        &self.typ
+    }
+
+    pub fn into_parts(self) -> (u16, ConfigPacketType) {
+        (self.id, self.typ)
+    }
+
+    pub fn serialize_with_config<W>(
+        &self,
+        writer: &mut W,
+        config: &ProtocolConfig,
+    ) -> Result<()>
+    where
+        W: Write,
+    {
+        self.typ.check(config)?;
+
+        writer
+            .write_u16::<LittleEndian>(self.id)
+            .map_err(Error::PacketSerialize)?;
+        writer
+            .write_u8(self.typ.to_u8())
+            .map_err(Error::PacketSerialize)?;
+
+        match &self.typ {
+            ConfigPacketType::Cmd { index, params } => {
+                write_cmd(writer, *index, params)?;
+            }
+            ConfigPacketType::CmdBatch { cmds } => {
+                let cmds =
+                    cmds.iter().map(|(index, params)| (*index, &params[..]));
+                write_cmd_batch(writer, cmds)?;
+            }
+            ConfigPacketType::OnStatus(status) => {
+                write_status(writer, status)?
+            }
+            ConfigPacketType::Other(typ) => {
+                Packet::serialize_type(typ, writer)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn deserialize_with_config<R>(
+        reader: &mut R,
+        config: &ProtocolConfig,
+    ) -> Result<Self>
+    where
+        R: Read,
+    {
+        let id = reader
+            .read_u16::<LittleEndian>()
+            .map_err(decode_err("id"))?;
+        let typ = reader.read_u8().map_err(decode_err("type"))?;
+
+        let typ = match typ {
+            3 => {
+                let mut buf = vec![0; 1 + config.num_cmd_params];
+                read_cmd(reader, typ, &mut buf)?;
+
+                ConfigPacketType::Cmd {
+                    index: buf[0],
+                    params: buf.split_off(1),
+                }
+            }
+
+            9 => {
+                let mut status = vec![0; config.num_status_bytes];
+                read_status(reader, typ, &mut status)?;
+
+                ConfigPacketType::OnStatus(status)
+            }
+
+            10 => {
+                let cmds = read_cmd_batch(
+                    reader,
+                    typ,
+                    config.num_cmd_params,
+                    |buf| (buf[0], buf[1..].to_vec()),
+                )?;
+
+                ConfigPacketType::CmdBatch { cmds }
+            }
+
+            _ => {
+                let opts = DeserializeOptions {
+                    protocol_version: config.protocol_version,
+                    status_len: config.status_len,
+                    ..Default::default()
+                };
+                ConfigPacketType::Other(Packet::deserialize_type(
+                    reader, id, typ, &opts,
+                )?)
+            }
+        };
+
+        Ok(Self { id, typ })
+    }
+}
+
+// The Cmd, CmdBatch and OnStatus layouts for any width. `Packet` goes
+// through these with the fixed widths so the two paths can't drift apart.
+
+pub(crate) fn write_cmd<W>(
+    writer: &mut W,
+    index: u8,
+    params: &[u8],
+) -> Result<()>
+where
+    W: Write,
+{
+    writer.write_u8(index).map_err(Error::PacketSerialize)?;
+    writer.write_all(params).map_err(Error::PacketSerialize)?;
+
+    Ok(())
+}
+
+pub(crate) fn write_cmd_batch<'a, W, I>(writer: &mut W, cmds: I) -> Result<()>
+where
+    W: Write,
+    I: ExactSizeIterator<Item = (u8, &'a [u8])>,
+{
+    writer
+        .write_u8(cmds.len() as u8)
+        .map_err(Error::PacketSerialize)?;
+    for (index, params) in cmds {
+        write_cmd(writer, index, params)?;
+    }
+
+    Ok(())
+}
+
+pub(crate) fn write_status<W>(writer: &mut W, status: &[u8]) -> Result<()>
+where
+    W: Write,
+{
+    writer.write_all(status).map_err(Error::PacketSerialize)
+}
+
+/// `buf` gets the index followed by the params, read in one go so a
+/// truncated command reports everything it still needs.
+pub(crate) fn read_cmd<R>(
+    reader: &mut R,
+    typ: u8,
+    buf: &mut [u8],
+) -> Result<()>
+where
+    R: Read,
+{
+    let num_params = buf.len() - 1;
+    read_fields(reader, typ, &[("index", 1), ("params", num_params)], buf)
+}
+
+/// `entry` turns the index and params of each command, laid out like
+/// `read_cmd`, into an element of the batch.
+pub(crate) fn read_cmd_batch<R, T, F>(
+    reader: &mut R,
+    typ: u8,
+    num_params: usize,
+    mut entry: F,
+) -> Result<Vec<T>>
+where
+    R: Read,
+    F: FnMut(&[u8]) -> T,
+{
+    let mut count = [0; 1];
+    read_payload(reader, typ, "cmds_len", &mut count)?;
+    let count = count[0] as usize;
+    if count > MAX_BATCH_CMDS {
+        return Err(Error::TooManyBatchCmds(count));
+    }
+
+    let mut buf = vec![0; 1 + num_params];
+    let mut cmds = Vec::with_capacity(count);
+    for _ in 0..count {
+        read_cmd(reader, typ, &mut buf)?;
+        cmds.push(entry(&buf));
+    }
+
+    Ok(cmds)
+}
+
+pub(crate) fn read_status<R>(
+    reader: &mut R,
+    typ: u8,
+    buf: &mut [u8],
+) -> Result<()>
+where
+    R: Read,
+{
+    read_payload(reader, typ, "status", buf)
+}
+
+impl From<Packet> for ConfigPacket {
+    fn from(packet: Packet) -> Self {
+        let (id, typ) = packet.into_parts();
+        Self::new(id, typ.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(packet: &ConfigPacket, config: &ProtocolConfig) -> Vec<u8> {
+        let mut buf = Vec::new();
+        packet.serialize_with_config(&mut buf, config).unwrap();
+
+        let mut reader = &buf[..];
+        let decoded =
+            ConfigPacket::deserialize_with_config(&mut reader, config)
+                .unwrap();
+        assert!(reader.is_empty());
+        assert_eq!(&decoded, packet);
+
+        buf
+    }
+
+    #[test]
+    fn status_width_4() {
+        let config = ProtocolConfig {
+            num_status_bytes: 4,
+            ..Default::default()
+        };
+        let packet =
+            ConfigPacket::new(1, ConfigPacketType::OnStatus(vec![1, 2, 3, 4]));
+
+        let buf = roundtrip(&packet, &config);
+        assert_eq!(buf, [1, 0, 9, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn status_width_12() {
+        let config = ProtocolConfig {
+            num_status_bytes: 12,
+            ..Default::default()
+        };
+        let status = (0..12).collect::<Vec<u8>>();
+        let packet = ConfigPacket::new(2, ConfigPacketType::OnStatus(status));
+
+        let buf = roundtrip(&packet, &config);
+        assert_eq!(buf.len(), 3 + 12);
+    }
+
+    #[test]
+    fn cmd_params_width() {
+        let config = ProtocolConfig {
+            num_cmd_params: 2,
+            ..Default::default()
+        };
+        let packet = ConfigPacket::new(
+            3,
+            ConfigPacketType::Cmd {
+                index: 5,
+                params: vec![6, 7],
+            },
+        );
+        roundtrip(&packet, &config);
+
+        let batch = ConfigPacket::new(
+            4,
+            ConfigPacketType::CmdBatch {
+                cmds: vec![(1, vec![2, 3]), (4, vec![5, 6])],
+            },
+        );
+        roundtrip(&batch, &config);
+    }
+
+    #[test]
+    fn width_mismatch() {
+        let config = ProtocolConfig {
+            num_status_bytes: 4,
+            ..Default::default()
+        };
+
+        let mut buf = Vec::new();
+        let packet =
+            ConfigPacket::new(1, ConfigPacketType::OnStatus(vec![0; 12]));
+        let res = packet.serialize_with_config(&mut buf, &config);
+        assert!(matches!(
+            res,
+            Err(Error::PayloadWidthMismatch {
+                expected: 4,
+                given: 12
+            })
+        ));
+        assert!(buf.is_empty());
+
+        let packet = ConfigPacket::new(
+            1,
+            ConfigPacketType::Other(PacketType::OnStatus([0; 8])),
+        );
+        let res = packet.serialize_with_config(&mut buf, &config);
+        assert!(matches!(res, Err(Error::PayloadWidthMismatch { .. })));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn default_config_matches_packet() {
+        let packets = [
+            Packet::status_response(1, [1, 2, 3, 4, 5, 6, 7, 8]),
+            Packet::new(
+                2,
+                PacketType::Cmd {
+                    index: 3,
+                    params: [8; NUM_CMD_PARAMS],
+                },
+            ),
+            Packet::identify_request(3),
+            Packet::new(
+                4,
+                PacketType::Vendor {
+                    type_id: 250,
+                    payload: vec![1, 2],
+                },
+            ),
+        ];
+
+        let config = ProtocolConfig::default();
+        for packet in packets {
+            let mut expected = Vec::new();
+            packet.serialize(&mut expected).unwrap();
+
+            let packet = ConfigPacket::from(packet);
+            let mut buf = Vec::new();
+            packet.serialize_with_config(&mut buf, &config).unwrap();
+            assert_eq!(buf, expected);
+
+            let decoded =
+                ConfigPacket::deserialize_with_config(&mut &buf[..], &config)
+                    .unwrap();
+            assert_eq!(decoded, packet);
+        }
+    }
+
+    #[test]
+    fn truncated_status() {
+        let config = ProtocolConfig {
+            num_status_bytes: 12,
+            ..Default::default()
+        };
+        let buf = [1, 0, 9, 1, 2, 3, 4];
+        let res =
+            ConfigPacket::deserialize_with_config(&mut &buf[..], &config);
+        assert!(matches!(
+            res,
+            Err(Error::UnexpectedEof {
+                packet_type: 9,
+                field: "status",
+                needed: 8
+            })
+        ));
+    }
+
+    // Goes through the identify exchange and sizes the config from the
+    // identity the device reported
+    fn device_config(status_len: u8) -> ProtocolConfig {
+        let identity =
+            Identity::with_version("device".to_string(), 1, 0, 0, 1)
+                .unwrap()
+                .with_status_len(status_len);
+        let packet: ConfigPacket =
+            Packet::identify_response(1, identity).into();
+        let config = ProtocolConfig {
+            status_len: true,
+            ..Default::default()
+        };
+        let buf = roundtrip(&packet, &config);
+        assert_eq!(buf.last(), Some(&status_len));
+
+        match ConfigPacket::deserialize_with_config(&mut &buf[..], &config)
+            .unwrap()
+            .into_parts()
+        {
+            (_, ConfigPacketType::Other(PacketType::OnIdentify(identity))) => {
+                ProtocolConfig::from(&identity)
+            }
+            (_, typ) => panic!("unexpected packet {:?}", typ),
+        }
+    }
+
+    #[test]
+    fn identity_status_len_4() {
+        let config = device_config(4);
+        assert_eq!(config.num_status_bytes, 4);
+        assert_eq!(config.num_cmd_params, NUM_CMD_PARAMS);
+
+        let packet =
+            ConfigPacket::new(5, ConfigPacketType::OnStatus(vec![1, 2, 3, 4]));
+        roundtrip(&packet, &config);
+
+        // A full legacy frame leaves its last 4 status bytes behind
+        let mut legacy = Vec::new();
+        Packet::status_response(5, [0; NUM_STATUS_BYTES])
+            .serialize(&mut legacy)
+            .unwrap();
+        let mut reader = &legacy[..];
+        ConfigPacket::deserialize_with_config(&mut reader, &config).unwrap();
+        assert_eq!(reader.len(), 4);
+    }
+
+    #[test]
+    fn identity_status_len_12() {
+        let config = device_config(12);
+        assert_eq!(config.num_status_bytes, 12);
+
+        let status = (0..12).collect::<Vec<u8>>();
+        let packet = ConfigPacket::new(6, ConfigPacketType::OnStatus(status));
+        roundtrip(&packet, &config);
+
+        let res = ConfigPacket::new(
+            6,
+            ConfigPacketType::OnStatus(vec![0; NUM_STATUS_BYTES]),
+        )
+        .serialize_with_config(&mut Vec::new(), &config);
+        assert!(matches!(
+            res,
+            Err(Error::PayloadWidthMismatch {
+                expected: 12,
+                given: 8
+            })
+        ));
+    }
+}
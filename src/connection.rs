@@ -0,0 +1,108 @@
+use std::io::Write;
+
+use crate::{Packet, PacketKind, PacketType, Result};
+
+pub struct Connection<W>
+where
+    W: Write,
+{
+    writer: W,
+    next_id: u16,
+    disconnected: bool,
+}
+
+impl<W> Connection<W>
+where
+    W: Write,
+{
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            next_id: 0,
+            disconnected: false,
+        }
+    }
+
+    pub fn send(&mut self, packet: &Packet) -> Result<()> {
+        packet.serialize(&mut self.writer)?;
+        self.next_id = packet.id().wrapping_add(1);
+        // Anything sent after a Disconnect reopens the connection
+        self.disconnected = packet.kind() == PacketKind::Disconnect;
+
+        Ok(())
+    }
+}
+
+impl<W> Drop for Connection<W>
+where
+    W: Write,
+{
+    fn drop(&mut self) {
+        if self.disconnected {
+            return;
+        }
+
+        // Best effort, there is nothing left to report the error to
+        let packet = Packet::new(self.next_id, PacketType::Disconnect);
+        let _ = packet.serialize(&mut self.writer);
+        let _ = self.writer.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drop_sends_disconnect() {
+        let mut buf = Vec::new();
+        {
+            let mut connection = Connection::new(&mut buf);
+            connection.send(&Packet::status_request(4)).unwrap();
+        }
+
+        let mut reader = &buf[..];
+        let status = Packet::deserialize(&mut reader).unwrap();
+        assert_eq!(status.id(), 4);
+
+        let disconnect = Packet::deserialize(&mut reader).unwrap();
+        assert_eq!(disconnect.typ(), &PacketType::Disconnect);
+        assert_eq!(disconnect.id(), 5);
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn drop_after_disconnect() {
+        let mut buf = Vec::new();
+        {
+            let mut connection = Connection::new(&mut buf);
+            connection
+                .send(&Packet::new(7, PacketType::Disconnect))
+                .unwrap();
+        }
+
+        let mut reader = &buf[..];
+        let disconnect = Packet::deserialize(&mut reader).unwrap();
+        assert_eq!(disconnect.id(), 7);
+        assert!(reader.is_empty());
+
+        // Sending again after the Disconnect needs a new one on drop
+        let mut buf = Vec::new();
+        {
+            let mut connection = Connection::new(&mut buf);
+            connection
+                .send(&Packet::new(1, PacketType::Disconnect))
+                .unwrap();
+            connection.send(&Packet::status_request(2)).unwrap();
+        }
+
+        let mut reader = &buf[..];
+        for _ in 0..2 {
+            Packet::deserialize(&mut reader).unwrap();
+        }
+        let disconnect = Packet::deserialize(&mut reader).unwrap();
+        assert_eq!(disconnect.typ(), &PacketType::Disconnect);
+        assert_eq!(disconnect.id(), 3);
+        assert!(reader.is_empty());
+    }
+}
@@ -0,0 +1,233 @@
+use crate::{negotiate, Packet, PacketType, ResponseCode, PROTOCOL_VERSION};
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum HandshakeEvent {
+    Connected,
+    Rejected(ResponseCode),
+    Incompatible { remote: u8 },
+    Ignored,
+}
+
+#[derive(Default, Debug)]
+pub struct ControllerHandshake {
+    next_id: u16,
+    pending: Option<u16>,
+    connected: bool,
+    versioned: bool,
+    protocol_version: Option<u8>,
+}
+
+impl ControllerHandshake {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sends `PROTOCOL_VERSION` in the `Connect` request, the device has
+    /// to be decoding with `DeserializeOptions::protocol_version` as well.
+    pub fn versioned() -> Self {
+        Self {
+            versioned: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// `None` after connecting to a device that didn't send a version.
+    pub fn protocol_version(&self) -> Option<u8> {
+        self.protocol_version
+    }
+
+    pub fn begin_connect(
+        &mut self,
+        send_status: bool,
+        status_time: u16,
+    ) -> Packet {
+        let id = self.next_id();
+        self.pending = Some(id);
+
+        Packet::new(
+            id,
+            PacketType::Connect {
+                send_status,
+                status_time,
+                protocol_version: self.versioned.then_some(PROTOCOL_VERSION),
+            },
+        )
+    }
+
+    pub fn handle(&mut self, packet: &Packet) -> HandshakeEvent {
+        if self.pending != Some(packet.id()) {
+            return HandshakeEvent::Ignored;
+        }
+
+        match packet.typ() {
+            PacketType::OnConnect {
+                protocol_version: None,
+            } => {
+                self.pending = None;
+                self.connected = true;
+                self.protocol_version = None;
+                HandshakeEvent::Connected
+            }
+
+            PacketType::OnConnect {
+                protocol_version: Some(remote),
+            } => {
+                self.pending = None;
+
+                match negotiate(PROTOCOL_VERSION, *remote) {
+                    Ok(version) => {
+                        self.connected = true;
+                        self.protocol_version = Some(version);
+                        HandshakeEvent::Connected
+                    }
+                    Err(_) => {
+                        self.connected = false;
+                        HandshakeEvent::Incompatible { remote: *remote }
+                    }
+                }
+            }
+
+            PacketType::Error { code } => {
+                self.pending = None;
+                HandshakeEvent::Rejected(*code)
+            }
+
+            _ => HandshakeEvent::Ignored,
+        }
+    }
+
+    pub fn disconnect(&mut self) -> Packet {
+        self.pending = None;
+        self.connected = false;
+        self.protocol_version = None;
+
+        Packet::new(self.next_id(), PacketType::Disconnect)
+    }
+
+    fn next_id(&mut self) -> u16 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connect_disconnect_cycle() {
+        let mut handshake = ControllerHandshake::new();
+
+        let connect = handshake.begin_connect(true, 100);
+        assert!(matches!(
+            connect.typ(),
+            PacketType::Connect {
+                send_status: true,
+                status_time: 100,
+                ..
+            }
+        ));
+        assert!(!handshake.is_connected());
+
+        // A reply to some other request doesn't complete the handshake
+        let other = Packet::connect_response(connect.id().wrapping_add(1));
+        assert_eq!(handshake.handle(&other), HandshakeEvent::Ignored);
+
+        let reply = Packet::connect_response(connect.id());
+        assert_eq!(handshake.handle(&reply), HandshakeEvent::Connected);
+        assert!(handshake.is_connected());
+
+        let disconnect = handshake.disconnect();
+        assert_eq!(disconnect.typ(), &PacketType::Disconnect);
+        assert_ne!(disconnect.id(), connect.id());
+        assert!(!handshake.is_connected());
+    }
+
+    #[test]
+    fn connect_rejected() {
+        let mut handshake = ControllerHandshake::new();
+        let connect = handshake.begin_connect(false, 0);
+
+        let reply = Packet::new(
+            connect.id(),
+            PacketType::Error {
+                code: ResponseCode::Unknown,
+            },
+        );
+        assert_eq!(
+            handshake.handle(&reply),
+            HandshakeEvent::Rejected(ResponseCode::Unknown)
+        );
+        assert!(!handshake.is_connected());
+
+        // The request is no longer pending
+        let reply = Packet::connect_response(connect.id());
+        assert_eq!(handshake.handle(&reply), HandshakeEvent::Ignored);
+    }
+
+    fn versioned_reply(id: u16, protocol_version: u8) -> Packet {
+        Packet::new(
+            id,
+            PacketType::OnConnect {
+                protocol_version: Some(protocol_version),
+            },
+        )
+    }
+
+    #[test]
+    fn versioned_connect() {
+        let mut handshake = ControllerHandshake::versioned();
+        let connect = handshake.begin_connect(true, 100);
+        assert!(matches!(
+            connect.typ(),
+            PacketType::Connect {
+                protocol_version: Some(PROTOCOL_VERSION),
+                ..
+            }
+        ));
+
+        let reply = versioned_reply(connect.id(), PROTOCOL_VERSION);
+        assert_eq!(handshake.handle(&reply), HandshakeEvent::Connected);
+        assert_eq!(handshake.protocol_version(), Some(PROTOCOL_VERSION));
+
+        handshake.disconnect();
+        assert_eq!(handshake.protocol_version(), None);
+    }
+
+    #[test]
+    fn legacy_device() {
+        let mut handshake = ControllerHandshake::new();
+        let connect = handshake.begin_connect(true, 100);
+        assert!(matches!(
+            connect.typ(),
+            PacketType::Connect {
+                protocol_version: None,
+                ..
+            }
+        ));
+
+        let reply = Packet::connect_response(connect.id());
+        assert_eq!(handshake.handle(&reply), HandshakeEvent::Connected);
+        assert!(handshake.is_connected());
+        assert_eq!(handshake.protocol_version(), None);
+    }
+
+    #[test]
+    fn incompatible_device() {
+        let mut handshake = ControllerHandshake::versioned();
+        let connect = handshake.begin_connect(false, 0);
+
+        let reply = versioned_reply(connect.id(), 0);
+        assert_eq!(
+            handshake.handle(&reply),
+            HandshakeEvent::Incompatible { remote: 0 }
+        );
+        assert!(!handshake.is_connected());
+        assert_eq!(handshake.protocol_version(), None);
+    }
+}
@@ -1,32 +1,337 @@
-use std::io::{Read, Write};
+use std::io::{ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
 
 pub use byteorder::ReadBytesExt;
-use byteorder::{LittleEndian, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
 use enum_primitive_derive::Primitive;
 use num_traits::{FromPrimitive, ToPrimitive};
 
+pub mod config;
+pub mod connection;
+pub mod handshake;
+pub mod matcher;
+pub mod record;
+pub mod registry;
+pub mod rsnav_bits;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+
 pub const PACKET_START: u8 = 0x4e;
 pub const NUM_STATUS_BYTES: usize = 8;
 pub const NUM_CMD_PARAMS: usize = 8;
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+pub const MAX_NAME_LEN: usize = u8::MAX as usize;
+pub const MAX_NUM_CMDS: usize = u8::MAX as usize;
+pub const MAX_BATCH_CMDS: usize = 16;
+pub const MAX_FW_CHUNK: usize = 512;
+pub const MAX_PACKET_SIZE: usize = 3 + 6 + MAX_FW_CHUNK;
+pub const VENDOR_TYPE_START: u8 = 200;
+pub const MAX_VENDOR_PAYLOAD: usize = MAX_FW_CHUNK;
+
+// Every variable length payload has to stay within MAX_PACKET_SIZE so a
+// crafted length field can't make deserialize allocate more than that
+const _: () = assert!(3 + 5 + MAX_NAME_LEN <= MAX_PACKET_SIZE);
+const _: () =
+    assert!(3 + 1 + MAX_BATCH_CMDS * (1 + NUM_CMD_PARAMS) <= MAX_PACKET_SIZE);
+const _: () = assert!(3 + 2 + MAX_VENDOR_PAYLOAD <= MAX_PACKET_SIZE);
 
 #[derive(Debug)]
 pub enum Error {
     InvalidResponseCode(u8),
+    InvalidResetMode(u8),
+    InvalidBoolByte(u8),
+    InvalidDeltaMask(u8),
+    ProtocolVersionMismatch {
+        local: u8,
+        remote: u8,
+    },
+    InvalidVersion {
+        major: u8,
+        minor: u8,
+        patch: u8,
+    },
     InvalidPacketType,
+    TrailingPayload(usize),
+    UnexpectedEof {
+        packet_type: u8,
+        field: &'static str,
+        needed: usize,
+    },
+    BufferTooSmall {
+        needed: usize,
+        available: usize,
+    },
+    ReadBudgetExceeded,
+    Timeout,
+
+    CmdIndexOutOfRange {
+        index: u8,
+        num_cmds: usize,
+    },
+    UnknownCommand(String),
+    TooManyCommandNames {
+        given: usize,
+        num_cmds: usize,
+    },
+    DuplicateCommandName(String),
+    InsufficientFunctionParameters {
+        index: u8,
+        required: usize,
+        given: usize,
+    },
+    ZeroStatusTime,
+    TooManyBatchCmds(usize),
+    PayloadWidthMismatch {
+        expected: usize,
+        given: usize,
+    },
+    FirmwareChunkTooLarge(usize),
+    VendorTypeOutOfRange(u8),
+    VendorPayloadTooLarge(usize),
+
+    FieldDecode {
+        field: &'static str,
+        source: std::io::Error,
+    },
 
     PacketSerialize(std::io::Error),
-    PacketDeserialize(std::io::Error),
+    PacketChecksumMismatch,
 
     IdentitySerialize(std::io::Error),
-    IdentityDeserialize(std::io::Error),
     IdentityInvalidName(std::string::FromUtf8Error),
+    IdentityChecksumMismatch,
+    IdentityNameTooLong(usize),
+    IdentityTooManyCmds(usize),
+    IdentityNoCmds,
+    IdentityEmptyName,
 
     StateSerializeFailed(std::io::Error),
-    StateDeserializeFailed(std::io::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+pub fn negotiate(local: u8, remote: u8) -> Result<u8> {
+    let version = local.min(remote);
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(Error::ProtocolVersionMismatch { local, remote });
+    }
+
+    Ok(version)
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! trace {
+    ($($arg:tt)*) => { tracing::trace!($($arg)*) };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace {
+    ($($arg:tt)*) => {};
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! warn {
+    ($($arg:tt)*) => { tracing::warn!($($arg)*) };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! warn {
+    ($($arg:tt)*) => {};
+}
+
+struct XorChecksum<T> {
+    inner: T,
+    checksum: u8,
+}
+
+impl<T> XorChecksum<T> {
+    fn new(inner: T) -> Self {
+        Self { inner, checksum: 0 }
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        self.checksum = buf.iter().fold(self.checksum, |acc, b| acc ^ b);
+    }
+}
+
+impl<T> Read for XorChecksum<T>
+where
+    T: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<T> Write for XorChecksum<T>
+where
+    T: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct DeadlineReader<R> {
+    inner: R,
+    deadline: Instant,
+    timed_out: bool,
+}
+
+impl<R> DeadlineReader<R> {
+    const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+    fn new(inner: R, deadline: Instant) -> Self {
+        Self {
+            inner,
+            deadline,
+            timed_out: false,
+        }
+    }
+}
+
+impl<R> Read for DeadlineReader<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if Instant::now() >= self.deadline {
+                self.timed_out = true;
+                return Err(ErrorKind::TimedOut.into());
+            }
+
+            match self.inner.read(buf) {
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::WouldBlock |
+                            ErrorKind::TimedOut |
+                            ErrorKind::Interrupted
+                    ) =>
+                {
+                    std::thread::sleep(Self::POLL_INTERVAL);
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+struct ByteCounter<T> {
+    inner: T,
+    count: usize,
+}
+
+#[cfg(feature = "tracing")]
+impl<T> ByteCounter<T> {
+    fn new(inner: T) -> Self {
+        Self { inner, count: 0 }
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl<T> Read for ByteCounter<T>
+where
+    T: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n;
+        Ok(n)
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl<T> Write for ByteCounter<T>
+where
+    T: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn decode_err(field: &'static str) -> impl FnOnce(std::io::Error) -> Error {
+    move |source| Error::FieldDecode { field, source }
+}
+
+fn read_payload<R>(
+    reader: &mut R,
+    packet_type: u8,
+    field: &'static str,
+    buf: &mut [u8],
+) -> Result<()>
+where
+    R: Read,
+{
+    let len = buf.len();
+    read_fields(reader, packet_type, &[(field, len)], buf)
+}
+
+// Reads a fixed size payload in one go, `fields` lays out the name and
+// width of every field in `buf` so an error names the one it stopped in
+fn read_fields<R>(
+    reader: &mut R,
+    packet_type: u8,
+    fields: &[(&'static str, usize)],
+    buf: &mut [u8],
+) -> Result<()>
+where
+    R: Read,
+{
+    debug_assert_eq!(
+        fields.iter().map(|(_, len)| len).sum::<usize>(),
+        buf.len()
+    );
+
+    let field_at = |offset: usize| {
+        let mut end = 0;
+        for (field, len) in fields {
+            end += len;
+            if offset < end {
+                return *field;
+            }
+        }
+
+        fields.last().map_or("", |(field, _)| *field)
+    };
+
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => {
+                return Err(Error::UnexpectedEof {
+                    packet_type,
+                    field: field_at(read),
+                    needed: buf.len() - read,
+                })
+            }
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(decode_err(field_at(read))(e)),
+        }
+    }
+
+    Ok(())
+}
+
 #[derive(Copy, Clone, Primitive, PartialEq, Debug)]
 #[repr(u8)]
 pub enum ResponseCode {
@@ -37,11 +342,23 @@ pub enum ResponseCode {
     InsufficientFunctionParameters = 0x05,
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Primitive, PartialEq, Debug)]
+#[repr(u8)]
+pub enum ResetMode {
+    Soft = 0x00,
+    Hard = 0x01,
+    BootloaderEnter = 0x02,
+}
+
+#[derive(Clone, PartialEq, Debug)]
 pub enum PacketType {
+    /// `protocol_version` is only on the wire when it is `Some`, firmware
+    /// from before the byte was added sends and expects neither it nor the
+    /// one in `OnConnect`. See `DeserializeOptions::protocol_version`.
     Connect {
         send_status: bool,
         status_time: u16,
+        protocol_version: Option<u8>,
     },
     Disconnect,
     Error {
@@ -55,38 +372,228 @@ pub enum PacketType {
     Identify,
     Status,
 
-    OnConnect,
+    OnConnect {
+        protocol_version: Option<u8>,
+    },
     OnCmd,
     OnIdentify(Identity),
     OnStatus([u8; NUM_STATUS_BYTES]),
+
+    CmdBatch {
+        cmds: Vec<(u8, [u8; NUM_CMD_PARAMS])>,
+    },
+    Reset {
+        mode: ResetMode,
+    },
+
+    FirmwareBegin {
+        total_len: u32,
+        crc: u32,
+    },
+    FirmwareChunk {
+        offset: u32,
+        data: Vec<u8>,
+    },
+    FirmwareEnd,
+
+    Vendor {
+        type_id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+#[repr(u8)]
+pub enum PacketKind {
+    Connect = 0,
+    Disconnect = 1,
+    Error = 2,
+
+    Cmd = 3,
+    Identify = 4,
+    Status = 5,
+
+    OnConnect = 6,
+    OnCmd = 7,
+    OnIdentify = 8,
+    OnStatus = 9,
+
+    CmdBatch = 10,
+    Reset = 11,
+
+    FirmwareBegin = 12,
+    FirmwareChunk = 13,
+    FirmwareEnd = 14,
+
+    Vendor = VENDOR_TYPE_START,
 }
 
 impl PacketType {
-    fn to_u8(&self) -> u8 {
+    pub fn kind(&self) -> PacketKind {
         match self {
             PacketType::Connect {
                 send_status: _,
                 status_time: _,
-            } => 0,
-            PacketType::Disconnect => 1,
-            PacketType::Error { code: _ } => 2,
+                protocol_version: _,
+            } => PacketKind::Connect,
+            PacketType::Disconnect => PacketKind::Disconnect,
+            PacketType::Error { code: _ } => PacketKind::Error,
 
             PacketType::Cmd {
                 index: _,
                 params: _,
-            } => 3,
-            PacketType::Identify => 4,
-            PacketType::Status => 5,
+            } => PacketKind::Cmd,
+            PacketType::Identify => PacketKind::Identify,
+            PacketType::Status => PacketKind::Status,
+
+            PacketType::OnConnect {
+                protocol_version: _,
+            } => PacketKind::OnConnect,
+            PacketType::OnCmd => PacketKind::OnCmd,
+            PacketType::OnIdentify(_) => PacketKind::OnIdentify,
+            PacketType::OnStatus(_) => PacketKind::OnStatus,
+
+            PacketType::CmdBatch { cmds: _ } => PacketKind::CmdBatch,
+            PacketType::Reset { mode: _ } => PacketKind::Reset,
+
+            PacketType::FirmwareBegin {
+                total_len: _,
+                crc: _,
+            } => PacketKind::FirmwareBegin,
+            PacketType::FirmwareChunk { offset: _, data: _ } => {
+                PacketKind::FirmwareChunk
+            }
+            PacketType::FirmwareEnd => PacketKind::FirmwareEnd,
+
+            PacketType::Vendor {
+                type_id: _,
+                payload: _,
+            } => PacketKind::Vendor,
+        }
+    }
+
+    /// Params are counted as the leading nonzero bytes, so a command with
+    /// a spec can't take a zero in one of its required params.
+    pub fn validate_cmd(&self, specs: &[CommandSpec]) -> Result<()> {
+        let check = |index: u8, params: &[u8; NUM_CMD_PARAMS]| {
+            let spec = specs.iter().find(|spec| spec.index == index);
+            let given = params.iter().take_while(|p| **p != 0).count();
+
+            match spec {
+                Some(spec) if given < spec.min_params => {
+                    Err(Error::InsufficientFunctionParameters {
+                        index,
+                        required: spec.min_params,
+                        given,
+                    })
+                }
+                _ => Ok(()),
+            }
+        };
+
+        match self {
+            PacketType::Cmd { index, params } => check(*index, params),
+            PacketType::CmdBatch { cmds } => cmds
+                .iter()
+                .try_for_each(|(index, params)| check(*index, params)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Requests are sent by the controller to the device, responses (the
+    /// `On*` types and `Error`) go the other way. Vendor packets define
+    /// their own direction and count as neither.
+    pub fn is_request(&self) -> bool {
+        match self.kind() {
+            PacketKind::Connect |
+            PacketKind::Disconnect |
+            PacketKind::Cmd |
+            PacketKind::Identify |
+            PacketKind::Status |
+            PacketKind::CmdBatch |
+            PacketKind::Reset |
+            PacketKind::FirmwareBegin |
+            PacketKind::FirmwareChunk |
+            PacketKind::FirmwareEnd => true,
+
+            PacketKind::Error |
+            PacketKind::OnConnect |
+            PacketKind::OnCmd |
+            PacketKind::OnIdentify |
+            PacketKind::OnStatus |
+            PacketKind::Vendor => false,
+        }
+    }
+
+    pub fn is_response(&self) -> bool {
+        match self.kind() {
+            PacketKind::Error |
+            PacketKind::OnConnect |
+            PacketKind::OnCmd |
+            PacketKind::OnIdentify |
+            PacketKind::OnStatus => true,
+
+            PacketKind::Connect |
+            PacketKind::Disconnect |
+            PacketKind::Cmd |
+            PacketKind::Identify |
+            PacketKind::Status |
+            PacketKind::CmdBatch |
+            PacketKind::Reset |
+            PacketKind::FirmwareBegin |
+            PacketKind::FirmwareChunk |
+            PacketKind::FirmwareEnd |
+            PacketKind::Vendor => false,
+        }
+    }
+
+    // Everything that can make serialize fail on its own, so it can be
+    // checked before the header goes out
+    fn check(&self) -> Result<()> {
+        match self {
+            PacketType::OnIdentify(identity) => identity.check(),
+            PacketType::CmdBatch { cmds } if cmds.len() > MAX_BATCH_CMDS => {
+                Err(Error::TooManyBatchCmds(cmds.len()))
+            }
+            PacketType::FirmwareChunk { offset: _, data }
+                if data.len() > MAX_FW_CHUNK =>
+            {
+                Err(Error::FirmwareChunkTooLarge(data.len()))
+            }
+            PacketType::Vendor {
+                type_id,
+                payload: _,
+            } if *type_id < VENDOR_TYPE_START => {
+                Err(Error::VendorTypeOutOfRange(*type_id))
+            }
+            PacketType::Vendor {
+                type_id: _,
+                payload,
+            } if payload.len() > MAX_VENDOR_PAYLOAD => {
+                Err(Error::VendorPayloadTooLarge(payload.len()))
+            }
+            _ => Ok(()),
+        }
+    }
 
-            PacketType::OnConnect => 6,
-            PacketType::OnCmd => 7,
-            PacketType::OnIdentify(_) => 8,
-            PacketType::OnStatus(_) => 9,
+    pub(crate) fn to_u8(&self) -> u8 {
+        match self {
+            PacketType::Vendor {
+                type_id,
+                payload: _,
+            } => *type_id,
+            _ => self.kind() as u8,
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
+pub struct CommandSpec {
+    pub index: u8,
+    pub min_params: usize,
+}
+
+#[derive(Clone, PartialEq, Debug)]
 pub struct Packet {
     id: u16,
     typ: PacketType,
@@ -97,6 +604,46 @@ impl Packet {
         Self { id, typ }
     }
 
+    pub fn connect_request(
+        id: u16,
+        send_status: bool,
+        status_time: u16,
+    ) -> Self {
+        Self::new(
+            id,
+            PacketType::Connect {
+                send_status,
+                status_time,
+                protocol_version: None,
+            },
+        )
+    }
+
+    pub fn connect_response(id: u16) -> Self {
+        Self::new(
+            id,
+            PacketType::OnConnect {
+                protocol_version: None,
+            },
+        )
+    }
+
+    pub fn identify_request(id: u16) -> Self {
+        Self::new(id, PacketType::Identify)
+    }
+
+    pub fn identify_response(id: u16, identity: Identity) -> Self {
+        Self::new(id, PacketType::OnIdentify(identity))
+    }
+
+    pub fn status_request(id: u16) -> Self {
+        Self::new(id, PacketType::Status)
+    }
+
+    pub fn status_response(id: u16, status: [u8; NUM_STATUS_BYTES]) -> Self {
+        Self::new(id, PacketType::OnStatus(status))
+    }
+
     pub fn id(&self) -> u16 {
         self.id
     }
@@ -105,21 +652,128 @@ impl Packet {
         &self.typ
     }
 
+    pub fn kind(&self) -> PacketKind {
+        self.typ.kind()
+    }
+
+    pub fn eq_ignore_id(&self, other: &Packet) -> bool {
+        self.typ == other.typ
+    }
+
+    pub fn into_typ(self) -> PacketType {
+        self.typ
+    }
+
+    pub fn into_parts(self) -> (u16, PacketType) {
+        (self.id, self.typ)
+    }
+
+    pub fn as_identity(&self) -> Option<&Identity> {
+        match &self.typ {
+            PacketType::OnIdentify(identity) => Some(identity),
+            _ => None,
+        }
+    }
+
+    pub fn as_status(&self) -> Option<&[u8; NUM_STATUS_BYTES]> {
+        match &self.typ {
+            PacketType::OnStatus(status) => Some(status),
+            _ => None,
+        }
+    }
+
+    pub fn as_error(&self) -> Option<(u16, ResponseCode)> {
+        match &self.typ {
+            PacketType::Error { code } => Some((self.id, *code)),
+            _ => None,
+        }
+    }
+
+    pub fn as_cmd(&self) -> Option<(u8, &[u8; NUM_CMD_PARAMS])> {
+        match &self.typ {
+            PacketType::Cmd { index, params } => Some((*index, params)),
+            _ => None,
+        }
+    }
+
+    pub fn validate(&self, ctx: Option<&Identity>) -> Result<()> {
+        match &self.typ {
+            PacketType::Connect {
+                send_status: true,
+                status_time: 0,
+                protocol_version: _,
+            } => Err(Error::ZeroStatusTime),
+
+            PacketType::Cmd { index, params: _ } => match ctx {
+                Some(identity) if *index as usize >= identity.num_cmds => {
+                    Err(Error::CmdIndexOutOfRange {
+                        index: *index,
+                        num_cmds: identity.num_cmds,
+                    })
+                }
+                _ => Ok(()),
+            },
+
+            PacketType::CmdBatch { cmds } => match ctx {
+                Some(identity) => {
+                    let out_of_range = cmds.iter().find(|(index, _)| {
+                        *index as usize >= identity.num_cmds
+                    });
+                    match out_of_range {
+                        Some((index, _)) => Err(Error::CmdIndexOutOfRange {
+                            index: *index,
+                            num_cmds: identity.num_cmds,
+                        }),
+                        None => Ok(()),
+                    }
+                }
+                None => Ok(()),
+            },
+
+            PacketType::OnIdentify(identity) if identity.num_cmds == 0 => {
+                Err(Error::IdentityNoCmds)
+            }
+
+            _ => Ok(()),
+        }
+    }
+
     pub fn serialize<W>(&self, writer: &mut W) -> Result<()>
     where
         W: Write,
     {
+        self.typ.check()?;
+
+        #[cfg(feature = "tracing")]
+        let writer = &mut ByteCounter::new(writer);
+
         writer
             .write_u16::<LittleEndian>(self.id)
             .map_err(Error::PacketSerialize)?;
         writer
             .write_u8(self.typ.to_u8())
             .map_err(Error::PacketSerialize)?;
+        Self::serialize_type(&self.typ, writer)?;
 
-        match &self.typ {
+        trace!(
+            id = self.id,
+            typ = ?self.typ.kind(),
+            len = writer.count,
+            "serialized packet"
+        );
+
+        Ok(())
+    }
+
+    fn serialize_type<W>(typ: &PacketType, writer: &mut W) -> Result<()>
+    where
+        W: Write,
+    {
+        match typ {
             PacketType::Connect {
                 send_status,
                 status_time,
+                protocol_version,
             } => {
                 writer
                     .write_u8(*send_status as u8)
@@ -127,6 +781,11 @@ impl Packet {
                 writer
                     .write_u16::<LittleEndian>(*status_time)
                     .map_err(Error::PacketSerialize)?;
+                if let Some(protocol_version) = protocol_version {
+                    writer
+                        .write_u8(*protocol_version)
+                        .map_err(Error::PacketSerialize)?;
+                }
             }
             PacketType::Disconnect => {}
 
@@ -136,101 +795,622 @@ impl Packet {
             }
 
             PacketType::Cmd { index, params } => {
-                writer.write_u8(*index).map_err(Error::PacketSerialize)?;
-                writer.write(params).map_err(Error::PacketSerialize)?;
+                config::write_cmd(writer, *index, params)?;
             }
 
             PacketType::Identify => {}
             PacketType::Status => {}
-            PacketType::OnConnect => {}
+            PacketType::OnConnect {
+                protocol_version: Some(protocol_version),
+            } => {
+                writer
+                    .write_u8(*protocol_version)
+                    .map_err(Error::PacketSerialize)?;
+            }
+            PacketType::OnConnect {
+                protocol_version: None,
+            } => {}
             PacketType::OnCmd => {}
 
             PacketType::OnIdentify(identity) => identity.serialize(writer)?,
             PacketType::OnStatus(status) => {
-                writer.write(status).map_err(Error::PacketSerialize)?;
+                config::write_status(writer, status)?;
+            }
+
+            PacketType::CmdBatch { cmds } => {
+                let cmds =
+                    cmds.iter().map(|(index, params)| (*index, &params[..]));
+                config::write_cmd_batch(writer, cmds)?;
+            }
+            PacketType::Reset { mode } => {
+                let mode = mode.to_u8().unwrap();
+                writer.write_u8(mode).map_err(Error::PacketSerialize)?;
+            }
+
+            PacketType::FirmwareBegin { total_len, crc } => {
+                writer
+                    .write_u32::<LittleEndian>(*total_len)
+                    .map_err(Error::PacketSerialize)?;
+                writer
+                    .write_u32::<LittleEndian>(*crc)
+                    .map_err(Error::PacketSerialize)?;
+            }
+            PacketType::FirmwareChunk { offset, data } => {
+                writer
+                    .write_u32::<LittleEndian>(*offset)
+                    .map_err(Error::PacketSerialize)?;
+                writer
+                    .write_u16::<LittleEndian>(data.len() as u16)
+                    .map_err(Error::PacketSerialize)?;
+                writer.write_all(data).map_err(Error::PacketSerialize)?;
+            }
+            PacketType::FirmwareEnd => {}
+
+            PacketType::Vendor {
+                type_id: _,
+                payload,
+            } => {
+                writer
+                    .write_u16::<LittleEndian>(payload.len() as u16)
+                    .map_err(Error::PacketSerialize)?;
+                writer.write_all(payload).map_err(Error::PacketSerialize)?;
             }
         }
 
         Ok(())
     }
 
-    pub fn deserialize<R>(reader: &mut R) -> Result<Self>
+    pub fn serialize_with_opts<W>(
+        &self,
+        writer: &mut W,
+        opts: &SerializeOptions,
+    ) -> Result<()>
     where
-        R: Read,
+        W: Write,
     {
-        let id = reader
-            .read_u16::<LittleEndian>()
-            .map_err(Error::PacketDeserialize)?;
-        let typ = reader.read_u8().map_err(Error::PacketDeserialize)?;
+        if !opts.checksum {
+            return self.serialize(writer);
+        }
 
-        let typ = match typ {
-            0 => {
-                let send_status =
-                    reader.read_u8().map_err(Error::PacketDeserialize)?;
-                let send_status = send_status > 0;
-                let status_time = reader
-                    .read_u16::<LittleEndian>()
-                    .map_err(Error::PacketDeserialize)?;
+        let mut checked = XorChecksum::new(&mut *writer);
+        self.serialize(&mut checked)?;
+        let checksum = checked.checksum;
 
-                Ok(PacketType::Connect {
-                    send_status,
-                    status_time,
-                })
-            }
-            1 => Ok(PacketType::Disconnect),
-            2 => {
-                let code =
-                    reader.read_u8().map_err(Error::PacketDeserialize)?;
-                let code = ResponseCode::from_u8(code)
-                    .ok_or(Error::InvalidResponseCode(code))?;
+        writer.write_u8(checksum).map_err(Error::PacketSerialize)?;
 
-                Ok(PacketType::Error { code })
-            }
+        Ok(())
+    }
 
-            3 => {
-                let index =
-                    reader.read_u8().map_err(Error::PacketDeserialize)?;
+    /// Encodes every packet into one buffer and hands it to `writer`
+    /// with a single `write_all`.
+    pub fn serialize_batch<W>(packets: &[Packet], writer: &mut W) -> Result<()>
+    where
+        W: Write,
+    {
+        let mut buf = Vec::new();
+        for packet in packets {
+            packet.serialize(&mut buf)?;
+        }
 
-                let mut params = [0; NUM_CMD_PARAMS];
-                reader
-                    .read_exact(&mut params)
-                    .map_err(Error::PacketDeserialize)?;
+        writer.write_all(&buf).map_err(Error::PacketSerialize)?;
 
-                Ok(PacketType::Cmd { index, params })
-            }
+        Ok(())
+    }
 
-            4 => Ok(PacketType::Identify),
-            5 => Ok(PacketType::Status),
-            6 => Ok(PacketType::OnConnect),
-            7 => Ok(PacketType::OnCmd),
+    pub fn write_to_slice(&self, buf: &mut [u8]) -> Result<usize> {
+        let mut counter = CountingWriter::new();
+        self.serialize(&mut counter)?;
 
-            8 => {
-                let identity = Identity::deserialize(reader)?;
-                Ok(PacketType::OnIdentify(identity))
-            }
+        let needed = counter.count();
+        if needed > buf.len() {
+            return Err(Error::BufferTooSmall {
+                needed,
+                available: buf.len(),
+            });
+        }
 
-            9 => {
-                let mut status = [0; NUM_STATUS_BYTES];
-                reader
-                    .read_exact(&mut status)
-                    .map_err(Error::PacketDeserialize)?;
-                Ok(PacketType::OnStatus(status))
-            }
+        self.serialize(&mut &mut buf[..])?;
 
-            _ => Err(Error::InvalidPacketType),
-        };
+        Ok(needed)
+    }
+
+    /// Boolean fields are decoded leniently, any nonzero byte is `true`.
+    /// Use `deserialize_with_opts` with `strict_bools` to only accept 0
+    /// and 1. `Connect` and `OnConnect` are decoded without the protocol
+    /// version byte.
+    pub fn deserialize<R>(reader: &mut R) -> Result<Self>
+    where
+        R: Read,
+    {
+        Self::deserialize_inner(reader, &DeserializeOptions::default())
+    }
+
+    fn deserialize_inner<R>(
+        reader: &mut R,
+        opts: &DeserializeOptions,
+    ) -> Result<Self>
+    where
+        R: Read,
+    {
+        #[cfg(feature = "tracing")]
+        let reader = &mut ByteCounter::new(reader);
+
+        let id = reader
+            .read_u16::<LittleEndian>()
+            .map_err(decode_err("id"))?;
+        let typ = reader.read_u8().map_err(decode_err("type"))?;
+        let typ = Self::deserialize_type(reader, id, typ, opts)?;
+
+        trace!(
+            id,
+            typ = ?typ.kind(),
+            len = reader.count,
+            "deserialized packet"
+        );
 
-        let typ = typ?;
+        Ok(Packet { id, typ })
+    }
+
+    pub fn from_raw(id: u16, type_id: u8, payload: &[u8]) -> Result<Self> {
+        Self::from_raw_with_opts(
+            id,
+            type_id,
+            payload,
+            &DeserializeOptions::default(),
+        )
+    }
+
+    /// `payload` has no header to cover, so `opts.checksum` is ignored.
+    pub fn from_raw_with_opts(
+        id: u16,
+        type_id: u8,
+        payload: &[u8],
+        opts: &DeserializeOptions,
+    ) -> Result<Self> {
+        let mut reader = payload;
+        let typ = Self::deserialize_type(&mut reader, id, type_id, opts)?;
+        if !reader.is_empty() {
+            return Err(Error::TrailingPayload(reader.len()));
+        }
 
         Ok(Packet { id, typ })
     }
+
+    fn deserialize_type<R>(
+        reader: &mut R,
+        #[cfg_attr(not(feature = "tracing"), allow(unused_variables))] id: u16,
+        typ: u8,
+        opts: &DeserializeOptions,
+    ) -> Result<PacketType>
+    where
+        R: Read,
+    {
+        match typ {
+            0 => {
+                const FIELDS: [(&str, usize); 3] = [
+                    ("send_status", 1),
+                    ("status_time", 2),
+                    ("protocol_version", 1),
+                ];
+
+                let mut buf = [0; 4];
+                let (fields, len) = if opts.protocol_version {
+                    (&FIELDS[..], 4)
+                } else {
+                    (&FIELDS[..2], 3)
+                };
+                read_fields(reader, typ, fields, &mut buf[..len])?;
+                if opts.strict_bools && buf[0] > 1 {
+                    warn!(id, send_status = buf[0], "invalid bool byte");
+                    return Err(Error::InvalidBoolByte(buf[0]));
+                }
+                let send_status = buf[0] > 0;
+                let status_time = LittleEndian::read_u16(&buf[1..3]);
+                let protocol_version = opts.protocol_version.then_some(buf[3]);
+
+                Ok(PacketType::Connect {
+                    send_status,
+                    status_time,
+                    protocol_version,
+                })
+            }
+            1 => Ok(PacketType::Disconnect),
+            2 => {
+                let mut buf = [0; 1];
+                read_payload(reader, typ, "code", &mut buf)?;
+                let code = buf[0];
+                let code = match ResponseCode::from_u8(code) {
+                    Some(code) => code,
+                    None => {
+                        warn!(id, code, "invalid response code");
+                        return Err(Error::InvalidResponseCode(code));
+                    }
+                };
+
+                Ok(PacketType::Error { code })
+            }
+
+            3 => {
+                let mut buf = [0; 1 + NUM_CMD_PARAMS];
+                config::read_cmd(reader, typ, &mut buf)?;
+                let index = buf[0];
+
+                let mut params = [0; NUM_CMD_PARAMS];
+                params.copy_from_slice(&buf[1..]);
+
+                Ok(PacketType::Cmd { index, params })
+            }
+
+            4 => Ok(PacketType::Identify),
+            5 => Ok(PacketType::Status),
+            6 => {
+                let protocol_version = if opts.protocol_version {
+                    let mut buf = [0; 1];
+                    read_payload(reader, typ, "protocol_version", &mut buf)?;
+                    Some(buf[0])
+                } else {
+                    None
+                };
+
+                Ok(PacketType::OnConnect { protocol_version })
+            }
+            7 => Ok(PacketType::OnCmd),
+
+            8 => {
+                let identity = Identity::deserialize_with_opts(reader, opts)?;
+                Ok(PacketType::OnIdentify(identity))
+            }
+
+            9 => {
+                let mut status = [0; NUM_STATUS_BYTES];
+                config::read_status(reader, typ, &mut status)?;
+                Ok(PacketType::OnStatus(status))
+            }
+
+            10 => {
+                let cmds = config::read_cmd_batch(
+                    reader,
+                    typ,
+                    NUM_CMD_PARAMS,
+                    |buf| {
+                        let mut params = [0; NUM_CMD_PARAMS];
+                        params.copy_from_slice(&buf[1..]);
+                        (buf[0], params)
+                    },
+                )?;
+
+                Ok(PacketType::CmdBatch { cmds })
+            }
+            11 => {
+                let mut buf = [0; 1];
+                read_payload(reader, typ, "mode", &mut buf)?;
+                let mode = buf[0];
+                let mode = match ResetMode::from_u8(mode) {
+                    Some(mode) => mode,
+                    None => {
+                        warn!(id, mode, "invalid reset mode");
+                        return Err(Error::InvalidResetMode(mode));
+                    }
+                };
+
+                Ok(PacketType::Reset { mode })
+            }
+
+            12 => {
+                let mut buf = [0; 8];
+                read_fields(
+                    reader,
+                    typ,
+                    &[("total_len", 4), ("crc", 4)],
+                    &mut buf,
+                )?;
+                let total_len = LittleEndian::read_u32(&buf[0..4]);
+                let crc = LittleEndian::read_u32(&buf[4..8]);
+
+                Ok(PacketType::FirmwareBegin { total_len, crc })
+            }
+            13 => {
+                let mut buf = [0; 6];
+                read_fields(
+                    reader,
+                    typ,
+                    &[("offset", 4), ("data_len", 2)],
+                    &mut buf,
+                )?;
+                let offset = LittleEndian::read_u32(&buf[0..4]);
+                let len = LittleEndian::read_u16(&buf[4..6]) as usize;
+                if len > MAX_FW_CHUNK {
+                    return Err(Error::FirmwareChunkTooLarge(len));
+                }
+
+                let mut data = vec![0; len];
+                read_payload(reader, typ, "data", &mut data)?;
+
+                Ok(PacketType::FirmwareChunk { offset, data })
+            }
+            14 => Ok(PacketType::FirmwareEnd),
+
+            VENDOR_TYPE_START..=u8::MAX => {
+                let mut buf = [0; 2];
+                read_payload(reader, typ, "payload_len", &mut buf)?;
+                let len = LittleEndian::read_u16(&buf) as usize;
+                if len > MAX_VENDOR_PAYLOAD {
+                    return Err(Error::VendorPayloadTooLarge(len));
+                }
+
+                let mut payload = vec![0; len];
+                read_payload(reader, typ, "payload", &mut payload)?;
+
+                Ok(PacketType::Vendor {
+                    type_id: typ,
+                    payload,
+                })
+            }
+
+            _ => {
+                warn!(id, typ, "invalid packet type");
+                Err(Error::InvalidPacketType)
+            }
+        }
+    }
+
+    pub fn deserialize_with_opts<R>(
+        reader: &mut R,
+        opts: &DeserializeOptions,
+    ) -> Result<Self>
+    where
+        R: Read,
+    {
+        if !opts.checksum {
+            return Self::deserialize_inner(reader, opts);
+        }
+
+        let mut checked = XorChecksum::new(&mut *reader);
+        let packet = Self::deserialize_inner(&mut checked, opts)?;
+        let checksum = checked.checksum;
+
+        let expected = reader.read_u8().map_err(decode_err("checksum"))?;
+        if checksum != expected {
+            return Err(Error::PacketChecksumMismatch);
+        }
+
+        Ok(packet)
+    }
+
+    /// The deadline is only checked between reads, so `reader` has to be
+    /// non-blocking (returning `WouldBlock`) or have its own read timeout
+    /// for this to return `Error::Timeout` instead of blocking forever.
+    ///
+    /// The bytes of a packet that was only partly read when the deadline
+    /// passed are consumed and dropped, so after an `Error::Timeout` the
+    /// stream is out of sync and `reader` has to be treated as broken.
+    pub fn deserialize_timeout<R>(
+        reader: &mut R,
+        deadline: Instant,
+    ) -> Result<Self>
+    where
+        R: Read,
+    {
+        Self::deserialize_timeout_with_opts(
+            reader,
+            deadline,
+            &DeserializeOptions::default(),
+        )
+    }
+
+    pub fn deserialize_timeout_with_opts<R>(
+        reader: &mut R,
+        deadline: Instant,
+        opts: &DeserializeOptions,
+    ) -> Result<Self>
+    where
+        R: Read,
+    {
+        let mut reader = DeadlineReader::new(reader, deadline);
+        Self::deserialize_with_opts(&mut reader, opts).map_err(|e| {
+            if reader.timed_out {
+                Error::Timeout
+            } else {
+                e
+            }
+        })
+    }
+
+    pub fn deserialize_opt<R>(reader: &mut R) -> Result<Option<Self>>
+    where
+        R: Read,
+    {
+        Self::deserialize_opt_with_opts(reader, &DeserializeOptions::default())
+    }
+
+    pub fn deserialize_opt_with_opts<R>(
+        reader: &mut R,
+        opts: &DeserializeOptions,
+    ) -> Result<Option<Self>>
+    where
+        R: Read,
+    {
+        // Read the first byte by hand so a stream that ends between two
+        // packets can be told apart from one that ends inside a packet
+        let mut first = [0; 1];
+        loop {
+            match reader.read(&mut first) {
+                Ok(0) => return Ok(None),
+                Ok(_) => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(decode_err("id")(e)),
+            }
+        }
+
+        let mut reader = (&first[..]).chain(reader);
+        Self::deserialize_with_opts(&mut reader, opts).map(Some)
+    }
+
+    pub fn try_deserialize(buf: &[u8]) -> Result<DecodeResult> {
+        Self::try_deserialize_with_opts(buf, &DeserializeOptions::default())
+    }
+
+    pub fn try_deserialize_with_opts(
+        buf: &[u8],
+        opts: &DeserializeOptions,
+    ) -> Result<DecodeResult> {
+        const HEADER_LEN: usize = 3;
+        if buf.len() < HEADER_LEN {
+            return Ok(DecodeResult::Incomplete {
+                needed: HEADER_LEN - buf.len(),
+            });
+        }
+
+        let mut reader = buf;
+        match Self::deserialize_with_opts(&mut reader, opts) {
+            Ok(packet) => Ok(DecodeResult::Complete {
+                packet,
+                consumed: buf.len() - reader.len(),
+            }),
+            Err(Error::UnexpectedEof { needed, .. }) => {
+                Ok(DecodeResult::Incomplete { needed })
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct SerializeOptions {
+    pub checksum: bool,
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct DeserializeOptions {
+    pub checksum: bool,
+    pub strict_bools: bool,
+    /// Decode the protocol version byte of `Connect` and `OnConnect`.
+    /// Both sides have to agree on this, a peer without the byte is out
+    /// of sync after the first connect packet otherwise.
+    pub protocol_version: bool,
+    /// Decode the `status_len` byte at the end of `OnIdentify`. Same as
+    /// `protocol_version`, both sides have to agree on it.
+    pub status_len: bool,
+}
+
+#[derive(Debug)]
+pub enum DecodeResult {
+    Complete { packet: Packet, consumed: usize },
+    Incomplete { needed: usize },
+}
+
+#[derive(Default, Debug)]
+pub struct CountingWriter {
+    count: usize,
+}
+
+impl CountingWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.count += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct LimitedReader<R> {
+    inner: R,
+    remaining: usize,
+    exceeded: bool,
+    opts: DeserializeOptions,
+}
+
+impl<R> LimitedReader<R>
+where
+    R: Read,
+{
+    pub fn new(inner: R, budget: usize) -> Self {
+        Self::with_opts(inner, budget, DeserializeOptions::default())
+    }
+
+    /// `read_packet` decodes with `opts`.
+    pub fn with_opts(
+        inner: R,
+        budget: usize,
+        opts: DeserializeOptions,
+    ) -> Self {
+        Self {
+            inner,
+            remaining: budget,
+            exceeded: false,
+            opts,
+        }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    pub fn read_packet(&mut self) -> Result<Packet> {
+        let opts = self.opts.clone();
+        Packet::deserialize_with_opts(self, &opts).map_err(|e| {
+            if self.exceeded {
+                Error::ReadBudgetExceeded
+            } else {
+                e
+            }
+        })
+    }
+}
+
+impl<R> Read for LimitedReader<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.remaining == 0 {
+            // A stream that ends right at the budget is fine, it's only
+            // exceeded if there actually is more to read
+            let mut probe = [0; 1];
+            if self.inner.read(&mut probe)? == 0 {
+                return Ok(0);
+            }
+
+            self.exceeded = true;
+            return Err(std::io::Error::other("read budget exceeded"));
+        }
+
+        let len = buf.len().min(self.remaining);
+        let n = self.inner.read(&mut buf[..len])?;
+        self.remaining -= n;
+
+        Ok(n)
+    }
 }
 
-#[derive(Clone)]
+/// Packed as `major` in bits 10-15, `minor` in bits 4-9 and `patch` in
+/// bits 0-3, so major and minor go up to 63 and patch up to 15.
+#[derive(Clone, PartialEq)]
 #[repr(transparent)]
 pub struct Version(pub u16);
 
 impl Version {
+    pub const MAX_MAJOR: u8 = 0x3f;
+    pub const MAX_MINOR: u8 = 0x3f;
+    pub const MAX_PATCH: u8 = 0xf;
+
+    /// Out of range fields are truncated, use `try_new` to reject them.
     pub fn new(major: u8, minor: u8, patch: u8) -> Version {
         Self(
             ((major & 0x3f) as u16) << 10 |
@@ -239,6 +1419,21 @@ impl Version {
         )
     }
 
+    pub fn try_new(major: u8, minor: u8, patch: u8) -> Result<Version> {
+        if major > Self::MAX_MAJOR ||
+            minor > Self::MAX_MINOR ||
+            patch > Self::MAX_PATCH
+        {
+            return Err(Error::InvalidVersion {
+                major,
+                minor,
+                patch,
+            });
+        }
+
+        Ok(Self::new(major, minor, patch))
+    }
+
     pub fn major(&self) -> u8 {
         ((self.0 >> 10) & 0x3f) as u8
     }
@@ -250,6 +1445,14 @@ impl Version {
     pub fn patch(&self) -> u8 {
         ((self.0) & 0xf) as u8
     }
+
+    pub fn to_le_bytes(&self) -> [u8; 2] {
+        self.0.to_le_bytes()
+    }
+
+    pub fn from_le_bytes(bytes: [u8; 2]) -> Version {
+        Self(u16::from_le_bytes(bytes))
+    }
 }
 
 impl std::fmt::Debug for Version {
@@ -259,64 +1462,194 @@ impl std::fmt::Debug for Version {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, PartialEq, Debug)]
 pub struct Identity {
     pub name: String,
     pub version: Version,
     pub num_cmds: usize,
+    /// Only on the wire when it is `Some`, see
+    /// `DeserializeOptions::status_len`. `None` means the device sends
+    /// `NUM_STATUS_BYTES` status bytes.
+    pub status_len: Option<u8>,
 }
 
 impl Identity {
+    pub fn new(
+        name: String,
+        version: Version,
+        num_cmds: usize,
+        allow_empty: bool,
+    ) -> Result<Self> {
+        if name.is_empty() && !allow_empty {
+            return Err(Error::IdentityEmptyName);
+        }
+
+        if name.len() > MAX_NAME_LEN {
+            return Err(Error::IdentityNameTooLong(name.len()));
+        }
+
+        if num_cmds > MAX_NUM_CMDS {
+            return Err(Error::IdentityTooManyCmds(num_cmds));
+        }
+
+        Ok(Self {
+            name,
+            version,
+            num_cmds,
+            status_len: None,
+        })
+    }
+
+    pub fn with_version(
+        name: String,
+        major: u8,
+        minor: u8,
+        patch: u8,
+        num_cmds: usize,
+    ) -> Result<Self> {
+        let version = Version::try_new(major, minor, patch)?;
+        Self::new(name, version, num_cmds, false)
+    }
+
+    pub fn with_status_len(mut self, status_len: u8) -> Self {
+        self.status_len = Some(status_len);
+        self
+    }
+
+    pub fn is_named(&self) -> bool {
+        !self.name.is_empty()
+    }
+
+    pub fn num_status_bytes(&self) -> usize {
+        self.status_len.map_or(NUM_STATUS_BYTES, usize::from)
+    }
+
+    pub fn check(&self) -> Result<()> {
+        if self.num_cmds > MAX_NUM_CMDS {
+            return Err(Error::IdentityTooManyCmds(self.num_cmds));
+        }
+
+        if self.name.len() > MAX_NAME_LEN {
+            return Err(Error::IdentityNameTooLong(self.name.len()));
+        }
+
+        Ok(())
+    }
+
     pub fn serialize<W>(&self, writer: &mut W) -> Result<()>
     where
         W: Write,
     {
+        self.check()?;
+
         writer
             .write_u16::<LittleEndian>(self.version.0)
             .map_err(Error::IdentitySerialize)?;
-        // TODO(patrik): Check num_cmds
         writer
             .write_u8(self.num_cmds as u8)
             .map_err(Error::IdentitySerialize)?;
-        // TODO(patrik): Check name len
         writer
             .write_u8(self.name.len() as u8)
             .map_err(Error::IdentitySerialize)?;
         writer
-            .write(self.name.as_bytes())
+            .write_all(self.name.as_bytes())
             .map_err(Error::IdentitySerialize)?;
 
+        if let Some(status_len) = self.status_len {
+            writer
+                .write_u8(status_len)
+                .map_err(Error::IdentitySerialize)?;
+        }
+
         Ok(())
     }
 
+    /// Decodes the identity without the `status_len` byte.
     pub fn deserialize<R>(reader: &mut R) -> Result<Self>
     where
         R: Read,
     {
-        let version = reader
-            .read_u16::<LittleEndian>()
-            .map_err(Error::IdentityDeserialize)?;
-        let num_cmds = reader.read_u8().map_err(Error::IdentityDeserialize)?;
-        let num_cmds = num_cmds as usize;
-        let name_len = reader.read_u8().map_err(Error::IdentityDeserialize)?;
-        let name_len = name_len as usize;
-
-        let mut buf = vec![0; name_len];
-        reader
-            .read_exact(&mut buf)
-            .map_err(Error::IdentityDeserialize)?;
+        Self::deserialize_with_opts(reader, &DeserializeOptions::default())
+    }
+
+    /// Only `status_len` of `opts` applies to an identity.
+    pub fn deserialize_with_opts<R>(
+        reader: &mut R,
+        opts: &DeserializeOptions,
+    ) -> Result<Self>
+    where
+        R: Read,
+    {
+        const TYPE: u8 = PacketKind::OnIdentify as u8;
+
+        let mut header = [0; 4];
+        read_fields(
+            reader,
+            TYPE,
+            &[("version", 2), ("num_cmds", 1), ("name_len", 1)],
+            &mut header,
+        )?;
+        let version = Version(LittleEndian::read_u16(&header[0..2]));
+        let num_cmds = header[2] as usize;
+        let name_len = header[3] as usize;
+
+        // Everything after the header is known from here on, so read it
+        // in one go to report exactly how much is still needed
+        let has_status_len = opts.status_len;
+        let mut buf = vec![0; name_len + has_status_len as usize];
+        let fields = [("name", name_len), ("status_len", 1)];
+        let fields = if has_status_len {
+            &fields[..]
+        } else {
+            &fields[..1]
+        };
+        read_fields(reader, TYPE, fields, &mut buf)?;
+
+        let status_len = if has_status_len { buf.pop() } else { None };
         let name =
             String::from_utf8(buf).map_err(Error::IdentityInvalidName)?;
 
         Ok(Self {
             name,
-            version: Version(version),
+            version,
             num_cmds,
+            status_len,
         })
     }
+
+    pub fn serialize_checked<W>(&self, writer: &mut W) -> Result<()>
+    where
+        W: Write,
+    {
+        let mut checked = XorChecksum::new(&mut *writer);
+        self.serialize(&mut checked)?;
+        let checksum = checked.checksum;
+
+        writer
+            .write_u8(checksum)
+            .map_err(Error::IdentitySerialize)?;
+
+        Ok(())
+    }
+
+    pub fn deserialize_checked<R>(reader: &mut R) -> Result<Self>
+    where
+        R: Read,
+    {
+        let mut checked = XorChecksum::new(&mut *reader);
+        let identity = Self::deserialize(&mut checked)?;
+        let checksum = checked.checksum;
+
+        let expected = reader.read_u8().map_err(decode_err("checksum"))?;
+        if checksum != expected {
+            return Err(Error::IdentityChecksumMismatch);
+        }
+
+        Ok(identity)
+    }
 }
 
-#[derive(Clone, Default, Debug)]
+#[derive(Clone, Default, PartialEq, Eq, Hash, Debug)]
 pub struct RSNavState {
     pub led_bar: bool,
     pub led_bar_low_mode: bool,
@@ -346,6 +1679,77 @@ impl RSNavState {
         }
     }
 
+    pub const fn all_off() -> Self {
+        Self::new()
+    }
+
+    /// Sets every field directly, without going through the mutators, so
+    /// the result doesn't necessarily satisfy the relations they maintain
+    /// (e.g. `led_bar` only being on together with `high_beam`).
+    pub const fn all_on() -> Self {
+        Self {
+            led_bar: true,
+            led_bar_low_mode: true,
+            high_beam: true,
+            led_bar_active: true,
+
+            reverse_camera: true,
+            reverse_lights: true,
+            reverse: true,
+            reverse_lights_active: true,
+            trunk_lights: true,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::all_off();
+    }
+
+    /// Every field paired with its name, in declaration order.
+    pub fn fields(&self) -> [(&'static str, bool); 9] {
+        [
+            ("led_bar", self.led_bar),
+            ("led_bar_low_mode", self.led_bar_low_mode),
+            ("high_beam", self.high_beam),
+            ("led_bar_active", self.led_bar_active),
+            ("reverse_camera", self.reverse_camera),
+            ("reverse_lights", self.reverse_lights),
+            ("reverse", self.reverse),
+            ("reverse_lights_active", self.reverse_lights_active),
+            ("trunk_lights", self.trunk_lights),
+        ]
+    }
+
+    pub fn diff(&self, other: &RSNavState) -> RSNavStateDiff {
+        let changes = self
+            .fields()
+            .into_iter()
+            .zip(other.fields())
+            .filter(|((_, old), (_, new))| old != new)
+            .map(|((name, old), (_, new))| (name, old, new))
+            .collect();
+
+        RSNavStateDiff { changes }
+    }
+
+    pub fn compare_status(
+        &self,
+        status: &[u8; NUM_STATUS_BYTES],
+    ) -> RSNavStateDiff {
+        // The status buffer always holds the two state bytes
+        let reported = Self::from_status_bytes(status).unwrap();
+        self.diff(&reported)
+    }
+
+    pub fn track<F>(&mut self, f: F) -> RSNavStateDiff
+    where
+        F: FnOnce(&mut Self),
+    {
+        let old = self.clone();
+        f(self);
+        old.diff(self)
+    }
+
     pub fn set_led_bar_active(&mut self, on: bool) {
         self.led_bar_active = on;
 
@@ -416,17 +1820,19 @@ impl RSNavState {
     where
         W: Write,
     {
-        let b = (self.led_bar as u8) << 0 |
-            (self.led_bar_low_mode as u8) << 1 |
-            (self.high_beam as u8) << 2 |
-            (self.led_bar_active as u8) << 3;
+        use rsnav_bits::*;
+
+        let b = bit(self.led_bar, BYTE0_LED_BAR) |
+            bit(self.led_bar_low_mode, BYTE0_LED_BAR_LOW_MODE) |
+            bit(self.high_beam, BYTE0_HIGH_BEAM) |
+            bit(self.led_bar_active, BYTE0_LED_BAR_ACTIVE);
         writer.write_u8(b).map_err(Error::StateSerializeFailed)?;
 
-        let b = (self.reverse_camera as u8) << 0 |
-            (self.reverse_lights as u8) << 1 |
-            (self.reverse as u8) << 2 |
-            (self.reverse_lights_active as u8) << 3 |
-            (self.trunk_lights as u8) << 4;
+        let b = bit(self.reverse_camera, BYTE1_REVERSE_CAMERA) |
+            bit(self.reverse_lights, BYTE1_REVERSE_LIGHTS) |
+            bit(self.reverse, BYTE1_REVERSE) |
+            bit(self.reverse_lights_active, BYTE1_REVERSE_LIGHTS_ACTIVE) |
+            bit(self.trunk_lights, BYTE1_TRUNK_LIGHTS);
         writer.write_u8(b).map_err(Error::StateSerializeFailed)?;
 
         Ok(())
@@ -436,21 +1842,1933 @@ impl RSNavState {
     where
         R: Read,
     {
+        use rsnav_bits::*;
+
         let mut res = Self::default();
 
-        let data = reader.read_u8().map_err(Error::StateDeserializeFailed)?;
-        res.led_bar = data & (1 << 0) > 0;
-        res.led_bar_low_mode = data & (1 << 1) > 0;
-        res.high_beam = data & (1 << 2) > 0;
-        res.led_bar_active = data & (1 << 3) > 0;
+        let data = reader.read_u8().map_err(decode_err("state_byte0"))?;
+        res.led_bar = data & BYTE0_LED_BAR > 0;
+        res.led_bar_low_mode = data & BYTE0_LED_BAR_LOW_MODE > 0;
+        res.high_beam = data & BYTE0_HIGH_BEAM > 0;
+        res.led_bar_active = data & BYTE0_LED_BAR_ACTIVE > 0;
 
-        let data = reader.read_u8().map_err(Error::StateDeserializeFailed)?;
-        res.reverse_camera = data & (1 << 0) > 0;
-        res.reverse_lights = data & (1 << 1) > 0;
-        res.reverse = data & (1 << 2) > 0;
-        res.reverse_lights_active = data & (1 << 3) > 0;
-        res.trunk_lights = data & (1 << 4) > 0;
+        let data = reader.read_u8().map_err(decode_err("state_byte1"))?;
+        res.reverse_camera = data & BYTE1_REVERSE_CAMERA > 0;
+        res.reverse_lights = data & BYTE1_REVERSE_LIGHTS > 0;
+        res.reverse = data & BYTE1_REVERSE > 0;
+        res.reverse_lights_active = data & BYTE1_REVERSE_LIGHTS_ACTIVE > 0;
+        res.trunk_lights = data & BYTE1_TRUNK_LIGHTS > 0;
 
         Ok(res)
     }
+
+    /// Status bytes 0 and 1 hold the state in the same layout as
+    /// `serialize` (see `rsnav_bits`); the remaining bytes are reserved.
+    pub fn from_status_bytes(
+        bytes: &[u8; NUM_STATUS_BYTES],
+    ) -> Result<RSNavState> {
+        Self::deserialize(&mut &bytes[..])
+    }
+
+    pub fn to_status_bytes(&self) -> [u8; NUM_STATUS_BYTES] {
+        let mut bytes = [0; NUM_STATUS_BYTES];
+        // The two state bytes always fit in the status buffer
+        self.serialize(&mut &mut bytes[..]).unwrap();
+        bytes
+    }
+
+    pub fn to_on_status(&self) -> PacketType {
+        PacketType::OnStatus(self.to_status_bytes())
+    }
+
+    pub fn serialize_delta<W>(
+        &self,
+        prev: &RSNavState,
+        writer: &mut W,
+    ) -> Result<()>
+    where
+        W: Write,
+    {
+        let cur = self.to_u16().to_le_bytes();
+        let prev = prev.to_u16().to_le_bytes();
+
+        let mut mask = 0;
+        for (i, (cur, prev)) in cur.iter().zip(prev).enumerate() {
+            if *cur != prev {
+                mask |= 1 << i;
+            }
+        }
+        writer.write_u8(mask).map_err(Error::StateSerializeFailed)?;
+
+        for (i, b) in cur.iter().enumerate() {
+            if mask & (1 << i) > 0 {
+                writer.write_u8(*b).map_err(Error::StateSerializeFailed)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn apply_delta<R>(&mut self, reader: &mut R) -> Result<()>
+    where
+        R: Read,
+    {
+        // One bit for each of the two state bytes
+        const MASK_BITS: u8 = 0b11;
+
+        let mut bytes = self.to_u16().to_le_bytes();
+
+        let mask = reader.read_u8().map_err(decode_err("delta_mask"))?;
+        if mask & !MASK_BITS != 0 {
+            return Err(Error::InvalidDeltaMask(mask));
+        }
+
+        for (i, b) in bytes.iter_mut().enumerate() {
+            if mask & (1 << i) > 0 {
+                *b = reader.read_u8().map_err(decode_err("delta_byte"))?;
+            }
+        }
+
+        *self = Self::from_u16(u16::from_le_bytes(bytes));
+
+        Ok(())
+    }
+
+    /// Byte 0 of the serialized state ends up in the low byte.
+    pub fn to_u16(&self) -> u16 {
+        let mut bytes = [0; 2];
+        // Serialize writes exactly the two state bytes
+        self.serialize(&mut &mut bytes[..]).unwrap();
+        u16::from_le_bytes(bytes)
+    }
+
+    pub fn from_u16(value: u16) -> Self {
+        // Two bytes is all deserialize ever reads, so this can't fail
+        Self::deserialize(&mut &value.to_le_bytes()[..]).unwrap()
+    }
+
+    pub fn state_hash(&self) -> u16 {
+        self.to_u16()
+    }
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct RSNavStateDiff {
+    pub changes: Vec<(&'static str, bool, bool)>,
+}
+
+impl RSNavStateDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn contains(&self, field: &str) -> bool {
+        self.changes.iter().any(|(name, _, _)| *name == field)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn to_bytes(packet: &Packet) -> Vec<u8> {
+        let mut buf = Vec::new();
+        packet.serialize(&mut buf).unwrap();
+        buf
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tracing_test::traced_test]
+    #[test]
+    fn trace_on_decode() {
+        let buf = to_bytes(&Packet::status_request(7));
+        Packet::deserialize(&mut &buf[..]).unwrap();
+        assert!(logs_contain("deserialized packet"));
+
+        let res = Packet::deserialize(&mut &[7, 0, 99][..]);
+        assert!(matches!(res, Err(Error::InvalidPacketType)));
+        assert!(logs_contain("invalid packet type"));
+    }
+
+    #[test]
+    fn truncated_cmd() {
+        let packet = Packet::new(
+            1,
+            PacketType::Cmd {
+                index: 2,
+                params: [3; NUM_CMD_PARAMS],
+            },
+        );
+        let buf = to_bytes(&packet);
+
+        let res = Packet::deserialize(&mut &buf[..5]);
+        assert!(matches!(
+            res,
+            Err(Error::UnexpectedEof {
+                packet_type: 3,
+                field: "params",
+                needed: 7,
+            })
+        ));
+
+        let res = Packet::deserialize(&mut &buf[..3]);
+        assert!(matches!(
+            res,
+            Err(Error::UnexpectedEof {
+                packet_type: 3,
+                field: "index",
+                needed: 9,
+            })
+        ));
+    }
+
+    #[test]
+    fn truncated_on_status() {
+        let buf = to_bytes(&Packet::status_response(1, [1; NUM_STATUS_BYTES]));
+
+        let res = Packet::deserialize(&mut &buf[..3]);
+        assert!(matches!(
+            res,
+            Err(Error::UnexpectedEof {
+                packet_type: 9,
+                needed: NUM_STATUS_BYTES,
+                ..
+            })
+        ));
+
+        let res = Packet::deserialize(&mut &buf[..buf.len() - 1]);
+        assert!(matches!(
+            res,
+            Err(Error::UnexpectedEof {
+                packet_type: 9,
+                needed: 1,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn truncated_on_identify() {
+        let buf = to_bytes(&Packet::identify_response(1, identity("dev", 2)));
+
+        // Only the header size is known before name_len is read
+        let res = Packet::deserialize(&mut &buf[..3]);
+        assert!(matches!(
+            res,
+            Err(Error::UnexpectedEof {
+                packet_type: 8,
+                field: "version",
+                needed: 4,
+            })
+        ));
+
+        // After it the exact remainder is
+        let res = Packet::deserialize(&mut &buf[..3 + 4 + 1]);
+        assert!(matches!(
+            res,
+            Err(Error::UnexpectedEof {
+                packet_type: 8,
+                field: "name",
+                needed: 2,
+            })
+        ));
+
+        let res = Packet::try_deserialize(&buf[..buf.len() - 1]).unwrap();
+        assert!(matches!(res, DecodeResult::Incomplete { needed: 1 }));
+        let res = Packet::try_deserialize(&buf[..3 + 4]).unwrap();
+        assert!(matches!(res, DecodeResult::Incomplete { needed: 3 }));
+    }
+
+    #[test]
+    fn rsnav_all_on_off() {
+        let off = RSNavState::all_off();
+        assert_eq!(off, RSNavState::new());
+        assert!(off.fields().iter().all(|(_, on)| !on));
+
+        let on = RSNavState::all_on();
+        assert!(on.fields().iter().all(|(_, on)| *on));
+    }
+
+    #[test]
+    fn rsnav_clear() {
+        let mut state = RSNavState::all_on();
+        state.clear();
+        assert_eq!(state, RSNavState::all_off());
+    }
+
+    // Accepts at most `max` bytes per write call, like a non-blocking
+    // socket with a full send buffer
+    struct ShortWriter {
+        buf: Vec<u8>,
+        max: usize,
+    }
+
+    impl ShortWriter {
+        fn new(max: usize) -> Self {
+            Self {
+                buf: Vec::new(),
+                max,
+            }
+        }
+    }
+
+    impl Write for ShortWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(self.max);
+            self.buf.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn short_write_cmd() {
+        let packet = Packet::new(
+            1,
+            PacketType::Cmd {
+                index: 4,
+                params: [1, 2, 3, 4, 5, 6, 7, 8],
+            },
+        );
+
+        let mut writer = ShortWriter::new(3);
+        packet.serialize(&mut writer).unwrap();
+        assert_eq!(writer.buf, to_bytes(&packet));
+        assert_eq!(Packet::deserialize(&mut &writer.buf[..]).unwrap(), packet);
+    }
+
+    fn identity(name: &str, num_cmds: usize) -> Identity {
+        Identity::with_version(name.to_string(), 1, 2, 3, num_cmds).unwrap()
+    }
+
+    #[test]
+    fn short_write_on_identify() {
+        let packet = Packet::identify_response(2, identity("0123456789", 4));
+
+        let mut writer = ShortWriter::new(1);
+        packet.serialize(&mut writer).unwrap();
+        assert_eq!(writer.buf, to_bytes(&packet));
+        assert_eq!(Packet::deserialize(&mut &writer.buf[..]).unwrap(), packet);
+    }
+
+    #[test]
+    fn identity_checksum() {
+        let identity = identity("speedwagon", 4);
+
+        let mut buf = Vec::new();
+        identity.serialize_checked(&mut buf).unwrap();
+        let decoded = Identity::deserialize_checked(&mut &buf[..]).unwrap();
+        assert_eq!(decoded, identity);
+
+        // Header is 4 bytes, so this lands in the middle of the name
+        buf[4 + 5] ^= 0x01;
+        let res = Identity::deserialize_checked(&mut &buf[..]);
+        assert!(matches!(res, Err(Error::IdentityChecksumMismatch)));
+    }
+
+    #[test]
+    fn accessors() {
+        let identity = identity("device", 2);
+        let on_identify = Packet::identify_response(1, identity.clone());
+        let on_status = Packet::status_response(2, [5; NUM_STATUS_BYTES]);
+        let cmd = Packet::new(
+            3,
+            PacketType::Cmd {
+                index: 1,
+                params: [9; NUM_CMD_PARAMS],
+            },
+        );
+
+        assert_eq!(on_identify.as_identity(), Some(&identity));
+        assert_eq!(on_status.as_identity(), None);
+
+        assert_eq!(on_status.as_status(), Some(&[5; NUM_STATUS_BYTES]));
+        assert_eq!(cmd.as_status(), None);
+
+        assert_eq!(cmd.as_cmd(), Some((1, &[9; NUM_CMD_PARAMS])));
+        assert_eq!(on_identify.as_cmd(), None);
+
+        assert_eq!(on_identify.into_typ(), PacketType::OnIdentify(identity));
+    }
+
+    #[test]
+    fn rsnav_track_high_beam() {
+        let mut state = RSNavState::new();
+        state.set_led_bar_active(true);
+
+        let diff = state.track(|state| state.high_beam(true));
+        assert_eq!(
+            diff.changes,
+            [("led_bar", false, true), ("high_beam", false, true)]
+        );
+        assert!(diff.contains("high_beam"));
+        assert!(diff.contains("led_bar"));
+
+        let diff = state.track(|state| state.high_beam(true));
+        assert!(diff.is_empty());
+    }
+
+    #[test]
+    fn max_name_len() {
+        let name = "a".repeat(MAX_NAME_LEN);
+        let identity = Identity::new(name, Version::new(1, 0, 0), 1, false);
+        assert!(identity.unwrap().serialize(&mut Vec::new()).is_ok());
+
+        let name = "a".repeat(MAX_NAME_LEN + 1);
+        let res = Identity::new(name.clone(), Version::new(1, 0, 0), 1, false);
+        assert!(matches!(res, Err(Error::IdentityNameTooLong(_))));
+
+        // Bypass the constructor to check serialize itself
+        let identity = Identity {
+            name,
+            version: Version::new(1, 0, 0),
+            num_cmds: 1,
+            status_len: None,
+        };
+        let res = identity.serialize(&mut Vec::new());
+        assert!(matches!(
+            res,
+            Err(Error::IdentityNameTooLong(len)) if len == MAX_NAME_LEN + 1
+        ));
+    }
+
+    #[test]
+    fn max_num_cmds() {
+        let identity = Identity {
+            name: "device".to_string(),
+            version: Version::new(1, 0, 0),
+            num_cmds: MAX_NUM_CMDS + 1,
+            status_len: None,
+        };
+        let res = identity.serialize(&mut Vec::new());
+        assert!(matches!(res, Err(Error::IdentityTooManyCmds(_))));
+    }
+
+    #[test]
+    fn invalid_identity_writes_nothing() {
+        let identity = Identity {
+            name: "a".repeat(300),
+            version: Version::new(1, 0, 0),
+            num_cmds: 1,
+            status_len: None,
+        };
+        let packet = Packet::identify_response(1, identity);
+
+        let mut buf = Vec::new();
+        let res = packet.serialize(&mut buf);
+        assert!(matches!(res, Err(Error::IdentityNameTooLong(300))));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn validate_cmd_index() {
+        let identity = identity("device", 4);
+        let cmd = |index| {
+            Packet::new(
+                1,
+                PacketType::Cmd {
+                    index,
+                    params: [0; NUM_CMD_PARAMS],
+                },
+            )
+        };
+
+        assert!(cmd(3).validate(Some(&identity)).is_ok());
+        assert!(matches!(
+            cmd(4).validate(Some(&identity)),
+            Err(Error::CmdIndexOutOfRange {
+                index: 4,
+                num_cmds: 4
+            })
+        ));
+        assert!(cmd(4).validate(None).is_ok());
+    }
+
+    #[test]
+    fn validate_connect_and_identity() {
+        let connect = Packet::connect_request(1, true, 0);
+        assert!(matches!(connect.validate(None), Err(Error::ZeroStatusTime)));
+        assert!(Packet::connect_request(1, false, 0).validate(None).is_ok());
+
+        let identity = Identity::new(
+            "device".to_string(),
+            Version::new(1, 0, 0),
+            0,
+            false,
+        )
+        .unwrap();
+        let packet = Packet::identify_response(1, identity);
+        assert!(matches!(packet.validate(None), Err(Error::IdentityNoCmds)));
+    }
+
+    #[test]
+    fn version_le_bytes() {
+        let version = Version::new(3, 17, 9);
+        let identity =
+            Identity::new("device".to_string(), version.clone(), 1, false)
+                .unwrap();
+
+        let mut buf = Vec::new();
+        identity.serialize(&mut buf).unwrap();
+        assert_eq!(version.to_le_bytes(), buf[0..2]);
+        assert_eq!(Version::from_le_bytes([buf[0], buf[1]]), version);
+    }
+
+    #[test]
+    fn try_deserialize_complete() {
+        let packet = Packet::status_response(1, [2; NUM_STATUS_BYTES]);
+        let mut buf = to_bytes(&packet);
+        buf.push(0xff);
+
+        match Packet::try_deserialize(&buf).unwrap() {
+            DecodeResult::Complete {
+                packet: decoded,
+                consumed,
+            } => {
+                assert_eq!(decoded, packet);
+                assert_eq!(consumed, buf.len() - 1);
+            }
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+
+    #[test]
+    fn try_deserialize_one_short() {
+        let buf = to_bytes(&Packet::status_response(1, [2; NUM_STATUS_BYTES]));
+        let res = Packet::try_deserialize(&buf[..buf.len() - 1]).unwrap();
+        assert!(matches!(res, DecodeResult::Incomplete { needed: 1 }));
+    }
+
+    #[test]
+    fn try_deserialize_empty() {
+        let res = Packet::try_deserialize(&[]).unwrap();
+        assert!(matches!(res, DecodeResult::Incomplete { needed: 3 }));
+    }
+
+    #[test]
+    fn rsnav_bits_match_serialize() {
+        use rsnav_bits::*;
+
+        let mut buf = Vec::new();
+        RSNavState::all_on().serialize(&mut buf).unwrap();
+
+        let byte0 = BYTE0_LED_BAR |
+            BYTE0_LED_BAR_LOW_MODE |
+            BYTE0_HIGH_BEAM |
+            BYTE0_LED_BAR_ACTIVE;
+        let byte1 = BYTE1_REVERSE_CAMERA |
+            BYTE1_REVERSE_LIGHTS |
+            BYTE1_REVERSE |
+            BYTE1_REVERSE_LIGHTS_ACTIVE |
+            BYTE1_TRUNK_LIGHTS;
+        assert_eq!(buf, [byte0, byte1]);
+    }
+
+    fn sample_state() -> RSNavState {
+        let mut state = RSNavState::new();
+        state.set_led_bar_active(true);
+        state.high_beam(true);
+        state.set_reverse_lights_active(true);
+        state.reverse(true);
+        state
+    }
+
+    #[test]
+    fn rsnav_status_bytes_roundtrip() {
+        let state = sample_state();
+        let bytes = state.to_status_bytes();
+        assert!(bytes[2..].iter().all(|b| *b == 0));
+        assert_eq!(RSNavState::from_status_bytes(&bytes).unwrap(), state);
+
+        // Reserved bytes are ignored
+        let mut bytes = bytes;
+        bytes[7] = 0xff;
+        assert_eq!(RSNavState::from_status_bytes(&bytes).unwrap(), state);
+    }
+
+    fn roundtrip(packet: &Packet) {
+        let buf = to_bytes(packet);
+        let mut reader = &buf[..];
+        assert_eq!(&Packet::deserialize(&mut reader).unwrap(), packet);
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn cmd_batch_empty() {
+        let packet = Packet::new(1, PacketType::CmdBatch { cmds: vec![] });
+        assert_eq!(to_bytes(&packet), [1, 0, 10, 0]);
+        roundtrip(&packet);
+    }
+
+    #[test]
+    fn cmd_batch_two() {
+        let cmds = vec![(1, [2; NUM_CMD_PARAMS]), (3, [4; NUM_CMD_PARAMS])];
+        let packet = Packet::new(1, PacketType::CmdBatch { cmds });
+        assert_eq!(to_bytes(&packet).len(), 4 + 2 * (1 + NUM_CMD_PARAMS));
+        roundtrip(&packet);
+    }
+
+    #[test]
+    fn cmd_batch_over_limit() {
+        let cmds = vec![(0, [0; NUM_CMD_PARAMS]); MAX_BATCH_CMDS + 1];
+        let packet = Packet::new(1, PacketType::CmdBatch { cmds });
+
+        let mut buf = Vec::new();
+        let res = packet.serialize(&mut buf);
+        assert!(matches!(
+            res,
+            Err(Error::TooManyBatchCmds(n)) if n == MAX_BATCH_CMDS + 1
+        ));
+        assert!(buf.is_empty());
+
+        let buf = [1, 0, 10, MAX_BATCH_CMDS as u8 + 1];
+        let res = Packet::deserialize(&mut &buf[..]);
+        assert!(matches!(res, Err(Error::TooManyBatchCmds(_))));
+    }
+
+    #[test]
+    fn clone_cmd() {
+        let packet = Packet::new(
+            1,
+            PacketType::Cmd {
+                index: 2,
+                params: [3; NUM_CMD_PARAMS],
+            },
+        );
+
+        let mut retry = packet.clone();
+        retry.id = 2;
+        assert_eq!(packet.id(), 1);
+        assert_eq!(retry.id(), 2);
+        assert_eq!(retry.typ(), packet.typ());
+    }
+
+    #[test]
+    fn counting_writer() {
+        let packets = [
+            Packet::identify_response(1, identity("device", 3)),
+            Packet::status_response(2, [0; NUM_STATUS_BYTES]),
+            Packet::new(3, PacketType::Disconnect),
+        ];
+
+        for packet in &packets {
+            let mut counter = CountingWriter::new();
+            packet.serialize(&mut counter).unwrap();
+            assert_eq!(counter.count(), to_bytes(packet).len());
+        }
+    }
+
+    #[test]
+    fn reset_modes() {
+        for mode in
+            [ResetMode::Soft, ResetMode::Hard, ResetMode::BootloaderEnter]
+        {
+            roundtrip(&Packet::new(1, PacketType::Reset { mode }));
+        }
+
+        let res = Packet::deserialize(&mut &[1, 0, 11, 3][..]);
+        assert!(matches!(res, Err(Error::InvalidResetMode(3))));
+    }
+
+    #[test]
+    fn firmware_sequence() {
+        let data = (0..=255).collect::<Vec<u8>>();
+        let packets = [
+            Packet::new(
+                1,
+                PacketType::FirmwareBegin {
+                    total_len: data.len() as u32,
+                    crc: 0xdeadbeef,
+                },
+            ),
+            Packet::new(
+                2,
+                PacketType::FirmwareChunk {
+                    offset: 0,
+                    data: data[..200].to_vec(),
+                },
+            ),
+            Packet::new(
+                3,
+                PacketType::FirmwareChunk {
+                    offset: 200,
+                    data: data[200..].to_vec(),
+                },
+            ),
+            Packet::new(4, PacketType::FirmwareEnd),
+        ];
+
+        for packet in &packets {
+            roundtrip(packet);
+        }
+    }
+
+    #[test]
+    fn firmware_chunk_too_large() {
+        let packet = Packet::new(
+            1,
+            PacketType::FirmwareChunk {
+                offset: 0,
+                data: vec![0; MAX_FW_CHUNK + 1],
+            },
+        );
+
+        let mut buf = Vec::new();
+        let res = packet.serialize(&mut buf);
+        assert!(matches!(
+            res,
+            Err(Error::FirmwareChunkTooLarge(len)) if len == MAX_FW_CHUNK + 1
+        ));
+        assert!(buf.is_empty());
+
+        let len = (MAX_FW_CHUNK as u16 + 1).to_le_bytes();
+        let buf = [1, 0, 13, 0, 0, 0, 0, len[0], len[1]];
+        let res = Packet::deserialize(&mut &buf[..]);
+        assert!(matches!(res, Err(Error::FirmwareChunkTooLarge(_))));
+    }
+
+    fn one_of_each() -> Vec<PacketType> {
+        vec![
+            PacketType::Connect {
+                send_status: true,
+                status_time: 10,
+                protocol_version: None,
+            },
+            PacketType::Disconnect,
+            PacketType::Error {
+                code: ResponseCode::InvalidCommand,
+            },
+            PacketType::Cmd {
+                index: 1,
+                params: [1, 2, 3, 4, 5, 6, 7, 8],
+            },
+            PacketType::Identify,
+            PacketType::Status,
+            PacketType::OnConnect {
+                protocol_version: None,
+            },
+            PacketType::OnCmd,
+            PacketType::OnIdentify(identity("device", 2)),
+            PacketType::OnStatus([1, 2, 3, 4, 5, 6, 7, 8]),
+            PacketType::CmdBatch {
+                cmds: vec![(1, [2; NUM_CMD_PARAMS])],
+            },
+            PacketType::Reset {
+                mode: ResetMode::Hard,
+            },
+            PacketType::FirmwareBegin {
+                total_len: 100,
+                crc: 5,
+            },
+            PacketType::FirmwareChunk {
+                offset: 0,
+                data: vec![1, 2, 3],
+            },
+            PacketType::FirmwareEnd,
+            PacketType::Vendor {
+                type_id: VENDOR_TYPE_START,
+                payload: vec![4, 5],
+            },
+        ]
+    }
+
+    #[test]
+    fn packet_kinds() {
+        let kinds = one_of_each()
+            .iter()
+            .map(|typ| typ.kind())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                PacketKind::Connect,
+                PacketKind::Disconnect,
+                PacketKind::Error,
+                PacketKind::Cmd,
+                PacketKind::Identify,
+                PacketKind::Status,
+                PacketKind::OnConnect,
+                PacketKind::OnCmd,
+                PacketKind::OnIdentify,
+                PacketKind::OnStatus,
+                PacketKind::CmdBatch,
+                PacketKind::Reset,
+                PacketKind::FirmwareBegin,
+                PacketKind::FirmwareChunk,
+                PacketKind::FirmwareEnd,
+                PacketKind::Vendor,
+            ]
+        );
+
+        // The kind discriminant is also the type byte on the wire
+        for typ in one_of_each() {
+            let kind = typ.kind();
+            let buf = to_bytes(&Packet::new(1, typ));
+            assert_eq!(buf[2], kind as u8);
+        }
+    }
+
+    #[test]
+    fn identity_empty_name() {
+        let version = Version::new(1, 0, 0);
+
+        let res = Identity::new(String::new(), version.clone(), 1, false);
+        assert!(matches!(res, Err(Error::IdentityEmptyName)));
+
+        let identity =
+            Identity::new(String::new(), version.clone(), 1, true).unwrap();
+        assert!(!identity.is_named());
+
+        // An empty name still decodes, it's up to the caller to check
+        let mut buf = Vec::new();
+        identity.serialize(&mut buf).unwrap();
+        let decoded = Identity::deserialize(&mut &buf[..]).unwrap();
+        assert!(!decoded.is_named());
+
+        let identity =
+            Identity::new("device".to_string(), version, 1, false).unwrap();
+        assert!(identity.is_named());
+    }
+
+    #[test]
+    fn crafted_lengths() {
+        // Maximum name length with no name bytes behind it
+        let buf = [1, 0, 8, 0, 0, 1, 0xff];
+        let res = Packet::deserialize(&mut &buf[..]);
+        assert!(matches!(
+            res,
+            Err(Error::UnexpectedEof {
+                packet_type: 8,
+                field: "name",
+                needed: 0xff,
+            })
+        ));
+
+        let buf = [1, 0, 8, 0, 0, 1, 2, 0xff, 0xfe];
+        let res = Packet::deserialize(&mut &buf[..]);
+        assert!(matches!(res, Err(Error::IdentityInvalidName(_))));
+
+        let buf = [1, 0, 13, 0, 0, 0, 0, 0xff, 0xff];
+        let res = Packet::deserialize(&mut &buf[..]);
+        assert!(matches!(res, Err(Error::FirmwareChunkTooLarge(0xffff))));
+
+        let buf = [1, 0, VENDOR_TYPE_START, 0xff, 0xff];
+        let res = Packet::deserialize(&mut &buf[..]);
+        assert!(matches!(res, Err(Error::VendorPayloadTooLarge(0xffff))));
+
+        let buf = [1, 0, 10, 0xff];
+        let res = Packet::deserialize(&mut &buf[..]);
+        assert!(matches!(res, Err(Error::TooManyBatchCmds(0xff))));
+    }
+
+    proptest! {
+        #[test]
+        fn deserialize_never_panics(
+            buf in prop::collection::vec(any::<u8>(), 0..64),
+        ) {
+            let _ = Packet::deserialize(&mut &buf[..]);
+            let _ = Packet::try_deserialize(&buf);
+        }
+    }
+
+    #[test]
+    fn rsnav_to_on_status() {
+        let state = sample_state();
+        let packet = Packet::new(1, state.to_on_status());
+
+        let status = packet.as_status().unwrap();
+        assert_eq!(RSNavState::from_status_bytes(status).unwrap(), state);
+        assert!(status[2..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn version_every_value() {
+        for major in 0..=Version::MAX_MAJOR {
+            for minor in 0..=Version::MAX_MINOR {
+                for patch in 0..=Version::MAX_PATCH {
+                    let version =
+                        Version::try_new(major, minor, patch).unwrap();
+                    let decoded =
+                        Version::from_le_bytes(version.to_le_bytes());
+                    assert_eq!(
+                        (decoded.major(), decoded.minor(), decoded.patch()),
+                        (major, minor, patch)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn version_no_bit_bleed() {
+        assert_eq!(Version::new(63, 0, 0).0, 0xfc00);
+        assert_eq!(Version::new(0, 63, 0).0, 0x03f0);
+        assert_eq!(Version::new(0, 0, 15).0, 0x000f);
+        assert_eq!(Version::new(63, 63, 15).0, 0xffff);
+
+        let version = Version::new(0, 63, 0);
+        assert_eq!((version.major(), version.patch()), (0, 0));
+        let version = Version::new(63, 0, 15);
+        assert_eq!(version.minor(), 0);
+    }
+
+    #[test]
+    fn version_try_new_bounds() {
+        assert!(Version::try_new(63, 63, 15).is_ok());
+
+        assert!(matches!(
+            Version::try_new(0, 0, 16),
+            Err(Error::InvalidVersion { patch: 16, .. })
+        ));
+        assert!(matches!(
+            Version::try_new(0, 64, 0),
+            Err(Error::InvalidVersion { minor: 64, .. })
+        ));
+        assert!(matches!(
+            Version::try_new(64, 0, 0),
+            Err(Error::InvalidVersion { major: 64, .. })
+        ));
+
+        // new truncates instead
+        assert_eq!(Version::new(0, 0, 16), Version::new(0, 0, 0));
+    }
+
+    #[test]
+    fn write_to_slice_exact() {
+        let packet = Packet::identify_response(1, identity("device", 2));
+        let expected = to_bytes(&packet);
+
+        let mut buf = vec![0; expected.len()];
+        assert_eq!(packet.write_to_slice(&mut buf).unwrap(), expected.len());
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn write_to_slice_one_short() {
+        let packet = Packet::identify_response(1, identity("device", 2));
+        let len = to_bytes(&packet).len();
+
+        let mut buf = vec![0; len - 1];
+        let res = packet.write_to_slice(&mut buf);
+        assert!(matches!(
+            res,
+            Err(Error::BufferTooSmall { needed, available })
+                if needed == len && available == len - 1
+        ));
+        assert!(buf.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn deserialize_opt_boundary() {
+        let mut buf = to_bytes(&Packet::status_request(1));
+        buf.extend(to_bytes(&Packet::identify_request(2)));
+
+        let mut reader = &buf[..];
+        let first = Packet::deserialize_opt(&mut reader).unwrap().unwrap();
+        assert_eq!(first.id(), 1);
+        let second = Packet::deserialize_opt(&mut reader).unwrap().unwrap();
+        assert_eq!(second.id(), 2);
+        assert!(Packet::deserialize_opt(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn deserialize_opt_mid_header() {
+        let mut buf = to_bytes(&Packet::status_request(1));
+        buf.push(2);
+
+        let mut reader = &buf[..];
+        assert!(Packet::deserialize_opt(&mut reader).unwrap().is_some());
+        assert!(Packet::deserialize_opt(&mut reader).is_err());
+    }
+
+    #[test]
+    fn cmd_params_order() {
+        let params = [1, 2, 3, 4, 5, 6, 7, 8];
+        let packet = Packet::new(9, PacketType::Cmd { index: 6, params });
+
+        let buf = to_bytes(&packet);
+        assert_eq!(buf, [9, 0, 3, 6, 1, 2, 3, 4, 5, 6, 7, 8]);
+        let decoded = Packet::deserialize(&mut &buf[..]).unwrap();
+        assert_eq!(decoded.as_cmd(), Some((6, &params)));
+
+        let mut writer = ShortWriter::new(1);
+        packet.serialize(&mut writer).unwrap();
+        assert_eq!(writer.buf, buf);
+        let decoded = Packet::deserialize(&mut &writer.buf[..]).unwrap();
+        assert_eq!(decoded.as_cmd(), Some((6, &params)));
+    }
+
+    #[test]
+    fn checksum_modes_roundtrip() {
+        let packet = Packet::status_response(3, [1, 2, 3, 4, 5, 6, 7, 8]);
+
+        for checksum in [false, true] {
+            let mut buf = Vec::new();
+            packet
+                .serialize_with_opts(&mut buf, &SerializeOptions { checksum })
+                .unwrap();
+
+            let opts = DeserializeOptions {
+                checksum,
+                ..Default::default()
+            };
+            let mut reader = &buf[..];
+            let decoded =
+                Packet::deserialize_with_opts(&mut reader, &opts).unwrap();
+            assert_eq!(decoded, packet);
+            assert!(reader.is_empty());
+        }
+    }
+
+    #[test]
+    fn checksum_mode_mismatch() {
+        let packet = Packet::status_response(3, [1, 2, 3, 4, 5, 6, 7, 8]);
+        let checked = DeserializeOptions {
+            checksum: true,
+            ..Default::default()
+        };
+
+        // A legacy peer's packet has no checksum byte to read
+        let buf = to_bytes(&packet);
+        let res = Packet::deserialize_with_opts(&mut &buf[..], &checked);
+        assert!(matches!(
+            res,
+            Err(Error::FieldDecode {
+                field: "checksum",
+                ..
+            })
+        ));
+
+        // Followed by another packet, its first byte is taken as the
+        // checksum and doesn't match
+        let mut buf = to_bytes(&packet);
+        buf.extend(to_bytes(&Packet::status_request(0x55)));
+        let res = Packet::deserialize_with_opts(&mut &buf[..], &checked);
+        assert!(matches!(res, Err(Error::PacketChecksumMismatch)));
+
+        let mut buf = Vec::new();
+        packet
+            .serialize_with_opts(
+                &mut buf,
+                &SerializeOptions { checksum: true },
+            )
+            .unwrap();
+        let last = buf.len() - 1;
+        buf[last] ^= 0xff;
+        let res = Packet::deserialize_with_opts(&mut &buf[..], &checked);
+        assert!(matches!(res, Err(Error::PacketChecksumMismatch)));
+    }
+
+    #[test]
+    fn rsnav_state_hash() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+
+        let hash = |state: &RSNavState| {
+            let mut hasher = DefaultHasher::new();
+            state.hash(&mut hasher);
+            hasher.finish()
+        };
+
+        let a = sample_state();
+        let b = sample_state();
+        assert_eq!(a, b);
+        assert_eq!(a.state_hash(), b.state_hash());
+        assert_eq!(hash(&a), hash(&b));
+
+        let mut c = sample_state();
+        c.set_trunk_lights(true);
+        assert_ne!(a, c);
+        assert_ne!(a.state_hash(), c.state_hash());
+    }
+
+    #[test]
+    fn read_budget_under() {
+        let mut buf = to_bytes(&Packet::status_request(1));
+        buf.extend(to_bytes(&Packet::identify_request(2)));
+
+        let mut reader = LimitedReader::new(&buf[..], 10);
+        assert_eq!(reader.read_packet().unwrap().id(), 1);
+        assert_eq!(reader.read_packet().unwrap().id(), 2);
+        assert_eq!(reader.remaining(), 4);
+    }
+
+    #[test]
+    fn read_budget_over() {
+        let mut buf = to_bytes(&Packet::status_request(1));
+        buf.extend(to_bytes(&Packet::identify_request(2)));
+
+        let mut reader = LimitedReader::new(&buf[..], 4);
+        assert_eq!(reader.read_packet().unwrap().id(), 1);
+        assert!(matches!(
+            reader.read_packet(),
+            Err(Error::ReadBudgetExceeded)
+        ));
+    }
+
+    #[test]
+    fn read_budget_exact_eof() {
+        let buf = to_bytes(&Packet::status_request(1));
+        assert_eq!(buf.len(), 3);
+
+        let mut reader = LimitedReader::new(&buf[..], 3);
+        assert_eq!(reader.read_packet().unwrap().id(), 1);
+        assert!(Packet::deserialize_opt(&mut reader).unwrap().is_none());
+    }
+
+    fn connect_bytes(send_status: u8) -> [u8; 6] {
+        [1, 0, 0, send_status, 100, 0]
+    }
+
+    #[test]
+    fn connect_lenient_bools() {
+        for (byte, expected) in [(0, false), (1, true), (255, true)] {
+            let buf = connect_bytes(byte);
+            let packet = Packet::deserialize(&mut &buf[..]).unwrap();
+            assert!(matches!(
+                packet.typ(),
+                PacketType::Connect {
+                    send_status,
+                    status_time: 100,
+                    ..
+                } if *send_status == expected
+            ));
+        }
+    }
+
+    #[test]
+    fn connect_strict_bools() {
+        let opts = DeserializeOptions {
+            strict_bools: true,
+            ..Default::default()
+        };
+
+        for (byte, expected) in [(0, false), (1, true)] {
+            let buf = connect_bytes(byte);
+            let packet =
+                Packet::deserialize_with_opts(&mut &buf[..], &opts).unwrap();
+            assert!(matches!(
+                packet.typ(),
+                PacketType::Connect { send_status, .. }
+                    if *send_status == expected
+            ));
+        }
+
+        let buf = connect_bytes(255);
+        let res = Packet::deserialize_with_opts(&mut &buf[..], &opts);
+        assert!(matches!(res, Err(Error::InvalidBoolByte(255))));
+    }
+
+    #[test]
+    fn identity_with_version() {
+        let identity =
+            Identity::with_version("device".to_string(), 2, 10, 3, 5).unwrap();
+        assert_eq!(identity.version, Version::new(2, 10, 3));
+        assert_eq!(identity.num_cmds, 5);
+
+        let res = Identity::with_version("device".to_string(), 64, 0, 0, 5);
+        assert!(matches!(res, Err(Error::InvalidVersion { .. })));
+
+        let res = Identity::with_version("device".to_string(), 0, 0, 16, 5);
+        assert!(matches!(res, Err(Error::InvalidVersion { .. })));
+
+        let res =
+            Identity::with_version("a".repeat(MAX_NAME_LEN + 1), 1, 0, 0, 5);
+        assert!(matches!(res, Err(Error::IdentityNameTooLong(_))));
+
+        let res = Identity::with_version(String::new(), 1, 0, 0, 5);
+        assert!(matches!(res, Err(Error::IdentityEmptyName)));
+
+        let res = Identity::with_version(
+            "device".to_string(),
+            1,
+            0,
+            0,
+            MAX_NUM_CMDS + 1,
+        );
+        assert!(matches!(res, Err(Error::IdentityTooManyCmds(_))));
+    }
+
+    #[test]
+    fn vendor_roundtrip() {
+        for type_id in [VENDOR_TYPE_START, u8::MAX] {
+            let payload = vec![1, 2, 3, 4];
+            let packet = Packet::new(
+                1,
+                PacketType::Vendor {
+                    type_id,
+                    payload: payload.clone(),
+                },
+            );
+
+            let buf = to_bytes(&packet);
+            assert_eq!(buf, [1, 0, type_id, 4, 0, 1, 2, 3, 4]);
+            roundtrip(&packet);
+        }
+    }
+
+    #[test]
+    fn vendor_out_of_range() {
+        let packet = Packet::new(
+            1,
+            PacketType::Vendor {
+                type_id: 5,
+                payload: vec![1, 2],
+            },
+        );
+
+        let mut buf = Vec::new();
+        let res = packet.serialize(&mut buf);
+        assert!(matches!(res, Err(Error::VendorTypeOutOfRange(5))));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn vendor_payload_too_large() {
+        let packet = Packet::new(
+            1,
+            PacketType::Vendor {
+                type_id: VENDOR_TYPE_START,
+                payload: vec![0; MAX_VENDOR_PAYLOAD + 1],
+            },
+        );
+
+        let mut buf = Vec::new();
+        let res = packet.serialize(&mut buf);
+        assert!(matches!(res, Err(Error::VendorPayloadTooLarge(_))));
+        assert!(buf.is_empty());
+    }
+
+    // Hands out one byte every `every` reads and `WouldBlock` otherwise
+    struct SlowReader<'a> {
+        buf: &'a [u8],
+        every: usize,
+        reads: usize,
+    }
+
+    impl Read for SlowReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.reads += 1;
+            if !self.reads.is_multiple_of(self.every) {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+
+            let n = self.buf.len().min(buf.len()).min(1);
+            buf[..n].copy_from_slice(&self.buf[..n]);
+            self.buf = &self.buf[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn deserialize_timeout_slow_reader() {
+        let buf = to_bytes(&Packet::status_response(1, [2; NUM_STATUS_BYTES]));
+
+        let mut reader = SlowReader {
+            buf: &buf,
+            every: 2,
+            reads: 0,
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let packet =
+            Packet::deserialize_timeout(&mut reader, deadline).unwrap();
+        assert_eq!(packet.id(), 1);
+
+        // Way too slow for the deadline
+        let mut reader = SlowReader {
+            buf: &buf,
+            every: 1000,
+            reads: 0,
+        };
+        let deadline = Instant::now() + Duration::from_millis(20);
+        let res = Packet::deserialize_timeout(&mut reader, deadline);
+        assert!(matches!(res, Err(Error::Timeout)));
+    }
+
+    #[test]
+    fn into_parts() {
+        let typ = PacketType::Reset {
+            mode: ResetMode::Soft,
+        };
+        let packet = Packet::new(42, typ.clone());
+
+        let (id, parts_typ) = packet.into_parts();
+        assert_eq!(id, 42);
+        assert_eq!(parts_typ, typ);
+    }
+
+    #[test]
+    fn compare_status_drift() {
+        let mut commanded = RSNavState::new();
+        commanded.set_led_bar_active(true);
+        commanded.high_beam(true);
+        assert!(commanded.led_bar);
+
+        let mut reported = commanded.clone();
+        reported.force_led_bar(false);
+
+        let diff = commanded.compare_status(&reported.to_status_bytes());
+        assert_eq!(diff.changes, [("led_bar", true, false)]);
+
+        let diff = commanded.compare_status(&commanded.to_status_bytes());
+        assert!(diff.is_empty());
+    }
+
+    #[test]
+    fn one_byte_writer_every_variant() {
+        for typ in one_of_each() {
+            let packet = Packet::new(0x1234, typ);
+
+            let mut writer = ShortWriter::new(1);
+            packet.serialize(&mut writer).unwrap();
+            assert_eq!(writer.buf, to_bytes(&packet), "{:?}", packet.kind());
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn one_byte_writer_arb_packet(packet in test_util::arb_packet()) {
+            let mut writer = ShortWriter::new(1);
+            packet.serialize(&mut writer).unwrap();
+            prop_assert_eq!(&writer.buf, &to_bytes(&packet));
+            let opts = test_util::opts_for(&packet);
+            let decoded =
+                Packet::deserialize_with_opts(&mut &writer.buf[..], &opts)
+                    .unwrap();
+            prop_assert_eq!(decoded, packet);
+        }
+    }
+
+    #[test]
+    fn delta_byte0_only() {
+        let prev = sample_state();
+        let mut cur = prev.clone();
+        cur.set_led_bar_low_mode(true);
+
+        let mut buf = Vec::new();
+        cur.serialize_delta(&prev, &mut buf).unwrap();
+        assert_eq!(buf, [0b01, cur.to_u16() as u8]);
+
+        let mut state = prev;
+        state.apply_delta(&mut &buf[..]).unwrap();
+        assert_eq!(state, cur);
+    }
+
+    #[test]
+    fn delta_no_change() {
+        let prev = sample_state();
+
+        let mut buf = Vec::new();
+        prev.serialize_delta(&prev, &mut buf).unwrap();
+        assert_eq!(buf, [0]);
+
+        let mut state = prev.clone();
+        state.apply_delta(&mut &buf[..]).unwrap();
+        assert_eq!(state, prev);
+    }
+
+    #[test]
+    fn delta_invalid_mask() {
+        let mut state = sample_state();
+        let res = state.apply_delta(&mut &[0xff, 0, 0][..]);
+        assert!(matches!(res, Err(Error::InvalidDeltaMask(0xff))));
+        assert_eq!(state, sample_state());
+    }
+
+    #[test]
+    fn request_response_classification() {
+        for typ in one_of_each() {
+            let (request, response) = match typ.kind() {
+                PacketKind::Connect |
+                PacketKind::Disconnect |
+                PacketKind::Cmd |
+                PacketKind::Identify |
+                PacketKind::Status |
+                PacketKind::CmdBatch |
+                PacketKind::Reset |
+                PacketKind::FirmwareBegin |
+                PacketKind::FirmwareChunk |
+                PacketKind::FirmwareEnd => (true, false),
+
+                PacketKind::Error |
+                PacketKind::OnConnect |
+                PacketKind::OnCmd |
+                PacketKind::OnIdentify |
+                PacketKind::OnStatus => (false, true),
+
+                PacketKind::Vendor => (false, false),
+            };
+
+            assert_eq!(typ.is_request(), request, "{:?}", typ.kind());
+            assert_eq!(typ.is_response(), response, "{:?}", typ.kind());
+        }
+
+        assert!(PacketType::Error {
+            code: ResponseCode::Success
+        }
+        .is_response());
+    }
+
+    #[test]
+    fn identity_status_len_legacy() {
+        // Without a status_len there is no byte for it on the wire, no
+        // matter the firmware version
+        let identity =
+            Identity::with_version("device".to_string(), 2, 1, 0, 2).unwrap();
+        assert_eq!(identity.status_len, None);
+        assert_eq!(identity.num_status_bytes(), NUM_STATUS_BYTES);
+
+        let mut buf = Vec::new();
+        identity.serialize(&mut buf).unwrap();
+        assert_eq!(buf.len(), 4 + "device".len());
+        assert_eq!(Identity::deserialize(&mut &buf[..]).unwrap(), identity);
+    }
+
+    #[test]
+    fn identity_status_len_versioned() {
+        let opts = DeserializeOptions {
+            status_len: true,
+            ..Default::default()
+        };
+        let identity = identity("device", 2).with_status_len(12);
+        assert_eq!(identity.num_status_bytes(), 12);
+
+        let mut buf = Vec::new();
+        identity.serialize(&mut buf).unwrap();
+        assert_eq!(buf.len(), 5 + "device".len());
+        assert_eq!(buf.last(), Some(&12));
+        assert_eq!(
+            Identity::deserialize_with_opts(&mut &buf[..], &opts).unwrap(),
+            identity
+        );
+
+        // A legacy decoder leaves the byte in the stream
+        let mut reader = &buf[..];
+        let decoded = Identity::deserialize(&mut reader).unwrap();
+        assert_eq!(decoded.status_len, None);
+        assert_eq!(reader, [12]);
+
+        // Cut right before the status_len byte
+        let res =
+            Identity::deserialize_with_opts(&mut &buf[..buf.len() - 1], &opts);
+        assert!(matches!(
+            res,
+            Err(Error::UnexpectedEof {
+                field: "status_len",
+                needed: 1,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn request_response_constructors() {
+        let packet = Packet::connect_request(1, true, 100);
+        assert_eq!(packet.id(), 1);
+        assert!(matches!(
+            packet.typ(),
+            PacketType::Connect {
+                send_status: true,
+                status_time: 100,
+                ..
+            }
+        ));
+
+        let packet = Packet::connect_response(2);
+        assert_eq!(packet.id(), 2);
+        assert_eq!(packet.kind(), PacketKind::OnConnect);
+
+        let packet = Packet::identify_request(3);
+        assert_eq!(packet.id(), 3);
+        assert_eq!(packet.typ(), &PacketType::Identify);
+
+        let packet = Packet::identify_response(4, identity("device", 2));
+        assert_eq!(packet.id(), 4);
+        assert_eq!(
+            packet.typ(),
+            &PacketType::OnIdentify(identity("device", 2))
+        );
+
+        let packet = Packet::status_request(5);
+        assert_eq!(packet.id(), 5);
+        assert_eq!(packet.typ(), &PacketType::Status);
+
+        let packet = Packet::status_response(6, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(packet.id(), 6);
+        assert_eq!(
+            packet.typ(),
+            &PacketType::OnStatus([1, 2, 3, 4, 5, 6, 7, 8])
+        );
+    }
+
+    #[test]
+    fn validate_cmd_min_params() {
+        let specs = [CommandSpec {
+            index: 2,
+            min_params: 3,
+        }];
+        let cmd = |index, params: &[u8]| {
+            let mut buf = [0; NUM_CMD_PARAMS];
+            buf[..params.len()].copy_from_slice(params);
+            PacketType::Cmd { index, params: buf }
+        };
+
+        assert!(cmd(2, &[1, 2, 3]).validate_cmd(&specs).is_ok());
+        assert!(cmd(2, &[1, 2, 3, 4]).validate_cmd(&specs).is_ok());
+
+        let res = cmd(2, &[1, 2]).validate_cmd(&specs);
+        assert!(matches!(
+            res,
+            Err(Error::InsufficientFunctionParameters {
+                index: 2,
+                required: 3,
+                given: 2
+            })
+        ));
+
+        // Only leading params count, the zero ends them
+        let res = cmd(2, &[1, 0, 3]).validate_cmd(&specs);
+        assert!(matches!(
+            res,
+            Err(Error::InsufficientFunctionParameters { given: 1, .. })
+        ));
+
+        // Commands without a spec aren't checked
+        assert!(cmd(1, &[]).validate_cmd(&specs).is_ok());
+
+        let batch = PacketType::CmdBatch {
+            cmds: vec![(1, [0; NUM_CMD_PARAMS]), (2, [1; NUM_CMD_PARAMS])],
+        };
+        assert!(batch.validate_cmd(&specs).is_ok());
+        let batch = PacketType::CmdBatch {
+            cmds: vec![(2, [1; NUM_CMD_PARAMS]), (2, [0; NUM_CMD_PARAMS])],
+        };
+        assert!(matches!(
+            batch.validate_cmd(&specs),
+            Err(Error::InsufficientFunctionParameters { given: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn eq_ignore_id_cmd() {
+        let typ = PacketType::Cmd {
+            index: 3,
+            params: [1, 2, 3, 4, 5, 6, 7, 8],
+        };
+        let a = Packet::new(1, typ.clone());
+        let b = Packet::new(42, typ);
+        assert!(a.eq_ignore_id(&b));
+        assert_ne!(a, b);
+
+        let c = Packet::new(
+            1,
+            PacketType::Cmd {
+                index: 4,
+                params: [1, 2, 3, 4, 5, 6, 7, 8],
+            },
+        );
+        assert!(!a.eq_ignore_id(&c));
+    }
+
+    #[test]
+    fn negotiate_matching() {
+        assert_eq!(
+            negotiate(PROTOCOL_VERSION, PROTOCOL_VERSION).unwrap(),
+            PROTOCOL_VERSION
+        );
+    }
+
+    #[test]
+    fn negotiate_newer_controller() {
+        // The controller steps down to what the older device speaks
+        assert_eq!(negotiate(3, 2).unwrap(), 2);
+        assert_eq!(negotiate(2, 3).unwrap(), 2);
+    }
+
+    #[test]
+    fn negotiate_incompatible() {
+        let res = negotiate(PROTOCOL_VERSION, MIN_PROTOCOL_VERSION - 1);
+        assert!(matches!(
+            res,
+            Err(Error::ProtocolVersionMismatch { local, remote: 0 })
+                if local == PROTOCOL_VERSION
+        ));
+    }
+
+    #[test]
+    fn connect_legacy_layout() {
+        // Firmware from before the protocol version byte
+        let packet = Packet::connect_request(1, true, 100);
+        let buf = to_bytes(&packet);
+        assert_eq!(buf, connect_bytes(1));
+        roundtrip(&packet);
+
+        let packet = Packet::connect_response(2);
+        assert_eq!(to_bytes(&packet), [2, 0, 6]);
+        roundtrip(&packet);
+    }
+
+    #[test]
+    fn connect_versioned_layout() {
+        let opts = DeserializeOptions {
+            protocol_version: true,
+            ..Default::default()
+        };
+
+        let packet = Packet::new(
+            1,
+            PacketType::Connect {
+                send_status: true,
+                status_time: 100,
+                protocol_version: Some(PROTOCOL_VERSION),
+            },
+        );
+        let buf = to_bytes(&packet);
+        assert_eq!(buf, [1, 0, 0, 1, 100, 0, PROTOCOL_VERSION]);
+        let mut reader = &buf[..];
+        assert_eq!(
+            Packet::deserialize_with_opts(&mut reader, &opts).unwrap(),
+            packet
+        );
+        assert!(reader.is_empty());
+
+        let packet = Packet::new(
+            2,
+            PacketType::OnConnect {
+                protocol_version: Some(PROTOCOL_VERSION),
+            },
+        );
+        let buf = to_bytes(&packet);
+        assert_eq!(buf, [2, 0, 6, PROTOCOL_VERSION]);
+        assert_eq!(
+            Packet::deserialize_with_opts(&mut &buf[..], &opts).unwrap(),
+            packet
+        );
+
+        // A legacy decoder leaves the byte in the stream
+        let mut reader = &buf[..];
+        Packet::deserialize(&mut reader).unwrap();
+        assert_eq!(reader, [PROTOCOL_VERSION]);
+    }
+
+    #[test]
+    fn truncated_before_num_cmds() {
+        let identity = identity("device", 2);
+        let mut buf = Vec::new();
+        identity.serialize(&mut buf).unwrap();
+
+        // The two version bytes come first
+        let res = Identity::deserialize(&mut &buf[..2]);
+        assert!(matches!(
+            res,
+            Err(Error::UnexpectedEof {
+                field: "num_cmds",
+                needed: 2,
+                ..
+            })
+        ));
+
+        let buf = to_bytes(&Packet::identify_response(1, identity));
+        let res = Packet::deserialize(&mut &buf[..3 + 2]);
+        assert!(matches!(
+            res,
+            Err(Error::UnexpectedEof {
+                packet_type: 8,
+                field: "num_cmds",
+                needed: 2,
+            })
+        ));
+    }
+
+    #[test]
+    fn truncated_field_names() {
+        let buf = connect_bytes(1);
+        let res = Packet::deserialize(&mut &buf[..4]);
+        assert!(matches!(
+            res,
+            Err(Error::UnexpectedEof {
+                field: "status_time",
+                needed: 2,
+                ..
+            })
+        ));
+
+        let opts = DeserializeOptions {
+            protocol_version: true,
+            ..Default::default()
+        };
+        let res = Packet::deserialize_with_opts(&mut &buf[..], &opts);
+        assert!(matches!(
+            res,
+            Err(Error::UnexpectedEof {
+                field: "protocol_version",
+                needed: 1,
+                ..
+            })
+        ));
+
+        let packet = Packet::new(
+            1,
+            PacketType::FirmwareBegin {
+                total_len: 100,
+                crc: 0xdeadbeef,
+            },
+        );
+        let buf = to_bytes(&packet);
+        let res = Packet::deserialize(&mut &buf[..3 + 4]);
+        assert!(matches!(
+            res,
+            Err(Error::UnexpectedEof {
+                field: "crc",
+                needed: 4,
+                ..
+            })
+        ));
+
+        let packet = Packet::new(
+            1,
+            PacketType::CmdBatch {
+                cmds: vec![(1, [2; NUM_CMD_PARAMS]), (3, [4; NUM_CMD_PARAMS])],
+            },
+        );
+        let buf = to_bytes(&packet);
+        let res = Packet::deserialize(&mut &buf[..buf.len() - 1]);
+        assert!(matches!(
+            res,
+            Err(Error::UnexpectedEof {
+                field: "params",
+                needed: 1,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn rsnav_fields() {
+        let state = RSNavState {
+            high_beam: true,
+            trunk_lights: true,
+            ..Default::default()
+        };
+        let fields = state.fields();
+        assert_eq!(fields.len(), 9);
+        assert_eq!(fields[0], ("led_bar", false));
+        assert_eq!(fields[2], ("high_beam", true));
+        assert_eq!(fields[8], ("trunk_lights", true));
+
+        // The false fields are listed too
+        let fields = RSNavState::all_off().fields();
+        assert!(fields.iter().all(|(_, value)| !value));
+        assert_eq!(fields.len(), 9);
+    }
+
+    #[test]
+    fn from_raw_cmd() {
+        let params = [1, 2, 3, 4, 5, 6, 7, 8];
+        let mut payload = vec![4];
+        payload.extend_from_slice(&params);
+
+        let packet = Packet::from_raw(7, PacketKind::Cmd as u8, &payload);
+        assert_eq!(
+            packet.unwrap(),
+            Packet::new(7, PacketType::Cmd { index: 4, params })
+        );
+    }
+
+    #[test]
+    fn from_raw_on_status() {
+        let status = [8, 7, 6, 5, 4, 3, 2, 1];
+        let packet = Packet::from_raw(9, PacketKind::OnStatus as u8, &status);
+        assert_eq!(packet.unwrap(), Packet::status_response(9, status));
+
+        // Same bytes deserialize would see after the header
+        let buf = to_bytes(&Packet::status_response(9, status));
+        assert_eq!(&buf[3..], status);
+    }
+
+    #[test]
+    fn from_raw_errors() {
+        let res = Packet::from_raw(1, PacketKind::Identify as u8, &[0]);
+        assert!(matches!(res, Err(Error::TrailingPayload(1))));
+
+        let res = Packet::from_raw(1, PacketKind::OnStatus as u8, &[0; 4]);
+        assert!(matches!(
+            res,
+            Err(Error::UnexpectedEof {
+                packet_type: 9,
+                needed: 4,
+                ..
+            })
+        ));
+
+        let res = Packet::from_raw(1, 100, &[]);
+        assert!(matches!(res, Err(Error::InvalidPacketType)));
+    }
+
+    // Counts the write calls that reach it
+    #[derive(Default)]
+    struct WriteCounter {
+        buf: Vec<u8>,
+        writes: usize,
+    }
+
+    impl Write for WriteCounter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.writes += 1;
+            self.buf.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn serialize_batch_concat() {
+        let packets = one_of_each()
+            .into_iter()
+            .enumerate()
+            .map(|(id, typ)| Packet::new(id as u16, typ))
+            .collect::<Vec<_>>();
+
+        let mut buf = Vec::new();
+        Packet::serialize_batch(&packets, &mut buf).unwrap();
+
+        let expected = packets.iter().flat_map(to_bytes).collect::<Vec<_>>();
+        assert_eq!(buf, expected);
+
+        let mut reader = &buf[..];
+        for packet in &packets {
+            assert_eq!(&Packet::deserialize(&mut reader).unwrap(), packet);
+        }
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn serialize_batch_single_write() {
+        let packets = (0..32)
+            .map(|id| {
+                Packet::status_response(id, [id as u8; NUM_STATUS_BYTES])
+            })
+            .collect::<Vec<_>>();
+
+        let mut writer = WriteCounter::default();
+        Packet::serialize_batch(&packets, &mut writer).unwrap();
+        assert_eq!(writer.writes, 1);
+        assert_eq!(writer.buf.len(), 32 * (3 + NUM_STATUS_BYTES));
+
+        // A packet that can't be encoded fails the batch before any write
+        let mut packets = packets;
+        packets.push(Packet::new(
+            99,
+            PacketType::CmdBatch {
+                cmds: vec![(0, [0; NUM_CMD_PARAMS]); MAX_BATCH_CMDS + 1],
+            },
+        ));
+        let mut writer = WriteCounter::default();
+        let res = Packet::serialize_batch(&packets, &mut writer);
+        assert!(matches!(res, Err(Error::TooManyBatchCmds(_))));
+        assert_eq!(writer.writes, 0);
+    }
+
+    proptest! {
+        #[test]
+        fn rsnav_u16_roundtrip(state in test_util::arb_rsnav_state()) {
+            let value = state.to_u16();
+            prop_assert_eq!(RSNavState::from_u16(value), state.clone());
+
+            let mut bytes = Vec::new();
+            state.serialize(&mut bytes).unwrap();
+            prop_assert_eq!(value as u8, bytes[0]);
+            prop_assert_eq!((value >> 8) as u8, bytes[1]);
+        }
+    }
+
+    #[test]
+    fn rsnav_u16_known_value() {
+        let state = RSNavState {
+            led_bar: true,
+            trunk_lights: true,
+            ..Default::default()
+        };
+
+        let mut bytes = Vec::new();
+        state.serialize(&mut bytes).unwrap();
+        assert_eq!(state.to_u16(), u16::from_le_bytes([bytes[0], bytes[1]]));
+        assert_eq!(state.to_u16() & 0xff, bytes[0] as u16);
+        assert_eq!(RSNavState::from_u16(state.to_u16()), state);
+        assert_eq!(RSNavState::all_off().to_u16(), 0);
+    }
+
+    #[test]
+    fn versioned_stream_decoders() {
+        let opts = DeserializeOptions {
+            protocol_version: true,
+            ..Default::default()
+        };
+        let connect = Packet::new(
+            1,
+            PacketType::Connect {
+                send_status: true,
+                status_time: 100,
+                protocol_version: Some(PROTOCOL_VERSION),
+            },
+        );
+        let status = Packet::status_request(2);
+        let mut buf = to_bytes(&connect);
+        buf.extend(to_bytes(&status));
+
+        match Packet::try_deserialize_with_opts(&buf, &opts).unwrap() {
+            DecodeResult::Complete { packet, consumed } => {
+                assert_eq!(packet, connect);
+                assert_eq!(consumed, 7);
+            }
+            res => panic!("unexpected {:?}", res),
+        }
+
+        let mut reader = &buf[..];
+        let decoded = Packet::deserialize_opt_with_opts(&mut reader, &opts);
+        assert_eq!(decoded.unwrap(), Some(connect.clone()));
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let decoded = Packet::deserialize_timeout_with_opts(
+            &mut reader,
+            deadline,
+            &opts,
+        );
+        assert_eq!(decoded.unwrap(), status);
+        assert!(reader.is_empty());
+
+        let mut reader = LimitedReader::with_opts(&buf[..], buf.len(), opts);
+        assert_eq!(reader.read_packet().unwrap(), connect);
+        assert_eq!(reader.read_packet().unwrap(), status);
+    }
+
+    #[test]
+    fn from_raw_versioned() {
+        let opts = DeserializeOptions {
+            protocol_version: true,
+            ..Default::default()
+        };
+        let payload = [1, 100, 0, PROTOCOL_VERSION];
+
+        let packet =
+            Packet::from_raw_with_opts(1, 0, &payload, &opts).unwrap();
+        assert!(matches!(
+            packet.typ(),
+            PacketType::Connect {
+                protocol_version: Some(PROTOCOL_VERSION),
+                ..
+            }
+        ));
+
+        // The legacy layout leaves the version byte over
+        let res = Packet::from_raw(1, 0, &payload);
+        assert!(matches!(res, Err(Error::TrailingPayload(1))));
+    }
 }
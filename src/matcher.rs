@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+
+use crate::{Packet, PacketKind, ResponseCode};
+
+#[derive(Default, Debug)]
+pub struct ErrorMatcher {
+    outstanding: HashMap<u16, PacketKind>,
+}
+
+impl ErrorMatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn track(&mut self, request: &Packet) {
+        self.outstanding.insert(request.id(), request.kind());
+    }
+
+    pub fn complete(&mut self, id: u16) -> Option<PacketKind> {
+        self.outstanding.remove(&id)
+    }
+
+    pub fn resolve(
+        &mut self,
+        reply: &Packet,
+    ) -> Option<(PacketKind, ResponseCode)> {
+        let (id, code) = reply.as_error()?;
+        let kind = self.complete(id)?;
+
+        Some((kind, code))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PacketType, NUM_CMD_PARAMS, NUM_STATUS_BYTES};
+
+    #[test]
+    fn match_error_to_cmd() {
+        let mut matcher = ErrorMatcher::new();
+
+        let cmd = Packet::new(
+            7,
+            PacketType::Cmd {
+                index: 1,
+                params: [0; NUM_CMD_PARAMS],
+            },
+        );
+        matcher.track(&cmd);
+        matcher.track(&Packet::status_request(8));
+
+        let reply = Packet::new(
+            7,
+            PacketType::Error {
+                code: ResponseCode::InvalidCommand,
+            },
+        );
+        assert_eq!(
+            matcher.resolve(&reply),
+            Some((PacketKind::Cmd, ResponseCode::InvalidCommand))
+        );
+
+        // Resolved requests are no longer outstanding
+        assert_eq!(matcher.resolve(&reply), None);
+        assert_eq!(matcher.complete(8), Some(PacketKind::Status));
+    }
+
+    #[test]
+    fn ignore_non_error_reply() {
+        let mut matcher = ErrorMatcher::new();
+        matcher.track(&Packet::status_request(1));
+
+        let reply = Packet::status_response(1, [0; NUM_STATUS_BYTES]);
+        assert_eq!(matcher.resolve(&reply), None);
+        assert_eq!(matcher.complete(1), Some(PacketKind::Status));
+    }
+}
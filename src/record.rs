@@ -0,0 +1,141 @@
+use std::io::{Read, Write};
+
+use crate::{DeserializeOptions, Packet, Result};
+
+pub struct PacketRecorder<W> {
+    writer: W,
+}
+
+impl<W> PacketRecorder<W>
+where
+    W: Write,
+{
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn record(&mut self, packet: &Packet) -> Result<()> {
+        packet.serialize(&mut self.writer)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+pub struct PacketPlayer<R> {
+    reader: R,
+    opts: DeserializeOptions,
+    done: bool,
+}
+
+impl<R> PacketPlayer<R>
+where
+    R: Read,
+{
+    pub fn new(reader: R) -> Self {
+        Self::with_opts(reader, DeserializeOptions::default())
+    }
+
+    /// Replays a recording of a session that decodes with `opts`, such as
+    /// one with a versioned handshake.
+    pub fn with_opts(reader: R, opts: DeserializeOptions) -> Self {
+        Self {
+            reader,
+            opts,
+            done: false,
+        }
+    }
+}
+
+impl<R> Iterator for PacketPlayer<R>
+where
+    R: Read,
+{
+    type Item = Result<Packet>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let res =
+            Packet::deserialize_opt_with_opts(&mut self.reader, &self.opts)
+                .transpose();
+        if !matches!(res, Some(Ok(_))) {
+            self.done = true;
+        }
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PacketType, NUM_CMD_PARAMS, NUM_STATUS_BYTES};
+
+    #[test]
+    fn record_and_replay() {
+        let packets = [
+            Packet::connect_request(1, true, 100),
+            Packet::new(
+                2,
+                PacketType::Cmd {
+                    index: 3,
+                    params: [4; NUM_CMD_PARAMS],
+                },
+            ),
+            Packet::status_response(3, [5; NUM_STATUS_BYTES]),
+        ];
+
+        let mut recorder = PacketRecorder::new(Vec::new());
+        for packet in &packets {
+            recorder.record(packet).unwrap();
+        }
+        let buf = recorder.into_inner();
+
+        let replayed = PacketPlayer::new(&buf[..])
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(replayed, packets);
+    }
+
+    #[test]
+    fn replay_stops_after_error() {
+        let mut buf = Vec::new();
+        Packet::status_request(1).serialize(&mut buf).unwrap();
+        buf.extend_from_slice(&[2, 0]);
+
+        let mut player = PacketPlayer::new(&buf[..]);
+        assert!(matches!(player.next(), Some(Ok(_))));
+        assert!(matches!(player.next(), Some(Err(_))));
+        assert!(player.next().is_none());
+    }
+
+    #[test]
+    fn replay_versioned_handshake() {
+        use crate::handshake::ControllerHandshake;
+
+        let mut handshake = ControllerHandshake::versioned();
+        let packets = [
+            handshake.begin_connect(true, 100),
+            Packet::status_request(1),
+        ];
+
+        let mut recorder = PacketRecorder::new(Vec::new());
+        for packet in &packets {
+            recorder.record(packet).unwrap();
+        }
+        let buf = recorder.into_inner();
+
+        let opts = DeserializeOptions {
+            protocol_version: true,
+            ..Default::default()
+        };
+        let replayed = PacketPlayer::with_opts(&buf[..], opts)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(replayed, packets);
+    }
+}
@@ -0,0 +1,126 @@
+use crate::{
+    Error, Identity, PacketType, Result, MAX_NUM_CMDS, NUM_CMD_PARAMS,
+};
+
+#[derive(Clone, Debug)]
+pub struct CommandRegistry {
+    names: Vec<String>,
+}
+
+impl CommandRegistry {
+    pub fn new(identity: &Identity, names: &[&str]) -> Result<Self> {
+        let num_cmds = identity.num_cmds.min(MAX_NUM_CMDS);
+        if names.len() > num_cmds {
+            return Err(Error::TooManyCommandNames {
+                given: names.len(),
+                num_cmds: identity.num_cmds,
+            });
+        }
+
+        for (i, name) in names.iter().enumerate() {
+            if names[..i].contains(name) {
+                return Err(Error::DuplicateCommandName(name.to_string()));
+            }
+        }
+
+        let names = names.iter().map(|name| name.to_string()).collect();
+
+        Ok(Self { names })
+    }
+
+    pub fn index_of(&self, name: &str) -> Option<u8> {
+        self.names
+            .iter()
+            .position(|n| n == name)
+            .map(|index| index as u8)
+    }
+
+    pub fn name_of(&self, index: u8) -> Option<&str> {
+        self.names.get(index as usize).map(|name| name.as_str())
+    }
+
+    pub fn cmd(
+        &self,
+        name: &str,
+        params: [u8; NUM_CMD_PARAMS],
+    ) -> Result<PacketType> {
+        let index = self
+            .index_of(name)
+            .ok_or_else(|| Error::UnknownCommand(name.to_string()))?;
+
+        Ok(PacketType::Cmd { index, params })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> CommandRegistry {
+        let identity =
+            Identity::with_version("device".to_string(), 1, 0, 0, 3).unwrap();
+        CommandRegistry::new(&identity, &["led_bar", "reverse"]).unwrap()
+    }
+
+    #[test]
+    fn known_name() {
+        let registry = registry();
+        assert_eq!(registry.index_of("reverse"), Some(1));
+        assert_eq!(registry.name_of(0), Some("led_bar"));
+
+        let params = [1; NUM_CMD_PARAMS];
+        let cmd = registry.cmd("reverse", params).unwrap();
+        assert_eq!(cmd, PacketType::Cmd { index: 1, params });
+    }
+
+    #[test]
+    fn unknown_name() {
+        let registry = registry();
+        assert_eq!(registry.index_of("trunk"), None);
+        assert_eq!(registry.name_of(2), None);
+
+        let res = registry.cmd("trunk", [0; NUM_CMD_PARAMS]);
+        assert!(
+            matches!(res, Err(Error::UnknownCommand(name)) if name == "trunk")
+        );
+    }
+
+    #[test]
+    fn too_many_names() {
+        let identity =
+            Identity::with_version("device".to_string(), 1, 0, 0, 1).unwrap();
+        let res = CommandRegistry::new(&identity, &["led_bar", "reverse"]);
+        assert!(matches!(
+            res,
+            Err(Error::TooManyCommandNames {
+                given: 2,
+                num_cmds: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn duplicate_name() {
+        let identity =
+            Identity::with_version("device".to_string(), 1, 0, 0, 3).unwrap();
+        let res = CommandRegistry::new(&identity, &["led_bar", "led_bar"]);
+        assert!(matches!(
+            res,
+            Err(Error::DuplicateCommandName(name)) if name == "led_bar"
+        ));
+    }
+
+    #[test]
+    fn index_out_of_range() {
+        // Every index the identity has is named, so only the range ends it
+        let identity =
+            Identity::with_version("device".to_string(), 1, 0, 0, 3).unwrap();
+        let registry =
+            CommandRegistry::new(&identity, &["led_bar", "reverse", "trunk"])
+                .unwrap();
+        assert_eq!(registry.name_of(2), Some("trunk"));
+
+        assert_eq!(registry.name_of(3), None);
+        assert_eq!(registry.name_of(255), None);
+    }
+}
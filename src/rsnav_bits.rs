@@ -0,0 +1,18 @@
+pub const BYTE0_LED_BAR: u8 = 1 << 0;
+pub const BYTE0_LED_BAR_LOW_MODE: u8 = 1 << 1;
+pub const BYTE0_HIGH_BEAM: u8 = 1 << 2;
+pub const BYTE0_LED_BAR_ACTIVE: u8 = 1 << 3;
+
+pub const BYTE1_REVERSE_CAMERA: u8 = 1 << 0;
+pub const BYTE1_REVERSE_LIGHTS: u8 = 1 << 1;
+pub const BYTE1_REVERSE: u8 = 1 << 2;
+pub const BYTE1_REVERSE_LIGHTS_ACTIVE: u8 = 1 << 3;
+pub const BYTE1_TRUNK_LIGHTS: u8 = 1 << 4;
+
+pub(crate) fn bit(on: bool, mask: u8) -> u8 {
+    if on {
+        mask
+    } else {
+        0
+    }
+}
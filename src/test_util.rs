@@ -0,0 +1,194 @@
+use proptest::option;
+use proptest::prelude::*;
+use proptest::strategy::LazyJust;
+
+use crate::{
+    DeserializeOptions, Identity, Packet, PacketType, RSNavState, ResetMode,
+    ResponseCode, Version, MAX_BATCH_CMDS, MAX_FW_CHUNK, MAX_NUM_CMDS,
+    MAX_VENDOR_PAYLOAD, NUM_CMD_PARAMS, NUM_STATUS_BYTES, VENDOR_TYPE_START,
+};
+
+pub fn arb_version() -> impl Strategy<Value = Version> {
+    (0..=0x3fu8, 0..=0x3fu8, 0..=0xfu8)
+        .prop_map(|(major, minor, patch)| Version::new(major, minor, patch))
+}
+
+pub fn arb_identity() -> impl Strategy<Value = Identity> {
+    // 63 chars of at most 4 bytes each keeps the name under
+    // MAX_NAME_LEN bytes
+    (
+        ".{0,63}",
+        arb_version(),
+        0..=MAX_NUM_CMDS,
+        option::of(any::<u8>()),
+    )
+        .prop_map(|(name, version, num_cmds, status_len)| Identity {
+            name,
+            version,
+            num_cmds,
+            status_len,
+        })
+}
+
+pub fn arb_response_code() -> impl Strategy<Value = ResponseCode> {
+    prop_oneof![
+        Just(ResponseCode::Success),
+        Just(ResponseCode::Unknown),
+        Just(ResponseCode::InvalidPacketType),
+        Just(ResponseCode::InvalidCommand),
+        Just(ResponseCode::InsufficientFunctionParameters),
+    ]
+}
+
+pub fn arb_reset_mode() -> impl Strategy<Value = ResetMode> {
+    prop_oneof![
+        Just(ResetMode::Soft),
+        Just(ResetMode::Hard),
+        Just(ResetMode::BootloaderEnter),
+    ]
+}
+
+pub fn arb_packet_type() -> impl Strategy<Value = PacketType> {
+    prop_oneof![
+        (any::<bool>(), any::<u16>(), option::of(any::<u8>())).prop_map(
+            |(send_status, status_time, protocol_version)| {
+                PacketType::Connect {
+                    send_status,
+                    status_time,
+                    protocol_version,
+                }
+            }
+        ),
+        LazyJust::new(|| PacketType::Disconnect),
+        arb_response_code().prop_map(|code| PacketType::Error { code }),
+        (any::<u8>(), any::<[u8; NUM_CMD_PARAMS]>())
+            .prop_map(|(index, params)| PacketType::Cmd { index, params }),
+        LazyJust::new(|| PacketType::Identify),
+        LazyJust::new(|| PacketType::Status),
+        option::of(any::<u8>()).prop_map(|protocol_version| {
+            PacketType::OnConnect { protocol_version }
+        }),
+        LazyJust::new(|| PacketType::OnCmd),
+        arb_identity().prop_map(PacketType::OnIdentify),
+        any::<[u8; NUM_STATUS_BYTES]>().prop_map(PacketType::OnStatus),
+        proptest::collection::vec(
+            (any::<u8>(), any::<[u8; NUM_CMD_PARAMS]>()),
+            0..=MAX_BATCH_CMDS
+        )
+        .prop_map(|cmds| PacketType::CmdBatch { cmds }),
+        arb_reset_mode().prop_map(|mode| PacketType::Reset { mode }),
+        (any::<u32>(), any::<u32>()).prop_map(|(total_len, crc)| {
+            PacketType::FirmwareBegin { total_len, crc }
+        }),
+        (
+            any::<u32>(),
+            proptest::collection::vec(any::<u8>(), 0..=MAX_FW_CHUNK)
+        )
+            .prop_map(|(offset, data)| PacketType::FirmwareChunk {
+                offset,
+                data
+            }),
+        LazyJust::new(|| PacketType::FirmwareEnd),
+        (
+            VENDOR_TYPE_START..=u8::MAX,
+            proptest::collection::vec(any::<u8>(), 0..=MAX_VENDOR_PAYLOAD)
+        )
+            .prop_map(|(type_id, payload)| PacketType::Vendor {
+                type_id,
+                payload
+            }),
+    ]
+}
+
+pub fn arb_packet() -> impl Strategy<Value = Packet> {
+    (any::<u16>(), arb_packet_type())
+        .prop_map(|(id, typ)| Packet::new(id, typ))
+}
+
+pub fn arb_rsnav_state() -> impl Strategy<Value = RSNavState> {
+    any::<[bool; 9]>().prop_map(|b| RSNavState {
+        led_bar: b[0],
+        led_bar_low_mode: b[1],
+        high_beam: b[2],
+        led_bar_active: b[3],
+
+        reverse_camera: b[4],
+        reverse_lights: b[5],
+        reverse: b[6],
+        reverse_lights_active: b[7],
+        trunk_lights: b[8],
+    })
+}
+
+/// The options that decode `packet` back, only a versioned `Connect` or
+/// `OnConnect` needs the protocol version byte and only an `OnIdentify`
+/// with a `status_len` needs that byte.
+pub fn opts_for(packet: &Packet) -> DeserializeOptions {
+    let protocol_version = matches!(
+        packet.typ(),
+        PacketType::Connect {
+            protocol_version: Some(_),
+            ..
+        } | PacketType::OnConnect {
+            protocol_version: Some(_),
+        }
+    );
+
+    let status_len = matches!(
+        packet.typ(),
+        PacketType::OnIdentify(Identity {
+            status_len: Some(_),
+            ..
+        })
+    );
+
+    DeserializeOptions {
+        protocol_version,
+        status_len,
+        ..Default::default()
+    }
+}
+
+pub fn assert_roundtrip(packet: &Packet) {
+    let mut buf = Vec::new();
+    packet
+        .serialize(&mut buf)
+        .expect("failed to serialize packet");
+
+    let mut reader = &buf[..];
+    let decoded =
+        Packet::deserialize_with_opts(&mut reader, &opts_for(packet))
+            .expect("failed to deserialize packet");
+    assert!(
+        reader.is_empty(),
+        "packet left {} bytes unread",
+        reader.len()
+    );
+    assert_eq!(packet, &decoded);
+
+    let mut reencoded = Vec::new();
+    decoded
+        .serialize(&mut reencoded)
+        .expect("failed to serialize decoded packet");
+    assert_eq!(buf, reencoded);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn packet_roundtrip(packet in arb_packet()) {
+            assert_roundtrip(&packet);
+        }
+
+        #[test]
+        fn rsnav_state_roundtrip(state in arb_rsnav_state()) {
+            let mut buf = Vec::new();
+            state.serialize(&mut buf).unwrap();
+            let decoded = RSNavState::deserialize(&mut &buf[..]).unwrap();
+            prop_assert_eq!(decoded, state);
+        }
+    }
+}